@@ -1,6 +1,7 @@
 use std::{
+    fmt,
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -8,6 +9,7 @@ use std::{
     time::Duration,
 };
 
+use anyhow::bail;
 use chrono::{Local, NaiveDateTime, TimeZone};
 use console::style;
 use data_encoding::BASE64;
@@ -16,15 +18,19 @@ use near_primitives::types::AccountId;
 use serde::{Deserialize, Serialize};
 use tokio::{select, time::sleep};
 use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
 
 use fc_client::{
     channel::CorrespondentId,
+    contacts::ContactStore,
     message::{
         chunk::ChunkedWriteStream,
         cleartext::CleartextMessage,
         stream::{ReadStream, WriteStream},
+        structured::Structured,
     },
-    messenger::Messenger,
+    messenger::{KeyAgreement, Messenger, SessionStore},
+    trust::{KeyChange, TrustStore},
     wallet::Wallet,
 };
 
@@ -32,13 +38,37 @@ mod highlight;
 mod line_editor;
 use line_editor::LineEditor;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize)]
 struct Environment {
     key_file_path: PathBuf,
     network: Option<String>,
-    messenger_secret_key: String,
+    messenger_secret_key: Zeroizing<String>,
     key_registry_account_id: AccountId,
     message_repository_account_id: AccountId,
+    trust_store_path: Option<PathBuf>,
+    session_store_path: Option<PathBuf>,
+    contact_store_path: Option<PathBuf>,
+    #[serde(default)]
+    double_ratchet: bool,
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Environment")
+            .field("key_file_path", &self.key_file_path)
+            .field("network", &self.network)
+            .field("messenger_secret_key", &"[REDACTED]")
+            .field("key_registry_account_id", &self.key_registry_account_id)
+            .field(
+                "message_repository_account_id",
+                &self.message_repository_account_id,
+            )
+            .field("trust_store_path", &self.trust_store_path)
+            .field("session_store_path", &self.session_store_path)
+            .field("contact_store_path", &self.contact_store_path)
+            .field("double_ratchet", &self.double_ratchet)
+            .finish()
+    }
 }
 
 fn network_rpc_url(network: Option<String>) -> String {
@@ -82,6 +112,88 @@ fn monitor_conversation(
     (kill, recv)
 }
 
+fn watch_key_changes(messenger: Arc<Messenger>) -> tokio::sync::mpsc::Receiver<KeyChange> {
+    let (send, recv) = tokio::sync::mpsc::channel(16);
+
+    tokio::spawn(async move {
+        loop {
+            if let Ok(changes) = messenger.poll_key_changes().await {
+                for change in changes {
+                    if send.send(change).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            sleep(Duration::from_secs(10)).await;
+        }
+    });
+
+    recv
+}
+
+fn parse_messenger_secret_key(encoded: &str) -> anyhow::Result<StaticSecret> {
+    let decoded = Zeroizing::new(BASE64.decode(encoded.as_bytes())?);
+    // Converted in place, so that no copy outlives the zeroizing buffers.
+    match <[u8; 32]>::try_from(decoded.as_slice()) {
+        Ok(bytes) => Ok(StaticSecret::from(bytes)),
+        Err(_) => bail!(
+            "MESSENGER_SECRET_KEY must be 32 bytes, not {}",
+            decoded.len()
+        ),
+    }
+}
+
+fn load_trust_store(path: &Path) -> anyhow::Result<TrustStore> {
+    if !path.exists() {
+        return Ok(TrustStore::default());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+async fn save_trust_store(path: Option<&Path>, messenger: &Messenger) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        std::fs::write(
+            path,
+            serde_json::to_vec(&messenger.export_trust_store().await)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn load_session_store(path: &Path) -> anyhow::Result<SessionStore> {
+    if !path.exists() {
+        return Ok(SessionStore::default());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+async fn save_session_store(path: Option<&Path>, messenger: &Messenger) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        std::fs::write(
+            path,
+            serde_json::to_vec(&messenger.export_sessions().await)?,
+        )?;
+    }
+    Ok(())
+}
+
+fn load_contact_store(path: &Path) -> anyhow::Result<ContactStore> {
+    if !path.exists() {
+        return Ok(ContactStore::default());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+async fn save_contact_store(path: Option<&Path>, messenger: &Messenger) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        std::fs::write(
+            path,
+            serde_json::to_vec(&messenger.export_contacts().await)?,
+        )?;
+    }
+    Ok(())
+}
+
 fn format_time(epoch_ms: i64) -> String {
     Local
         .from_utc_datetime(&NaiveDateTime::from_timestamp_millis(epoch_ms).unwrap())
@@ -108,18 +220,30 @@ async fn main() -> anyhow::Result<()> {
         signer.into(),
     ));
 
-    let messenger_secret_key: [u8; 32] = BASE64
-        .decode(env.messenger_secret_key.as_bytes())
-        .unwrap()
-        .try_into()
-        .unwrap();
-
-    let messenger = Arc::new(Messenger::new(
+    let mut messenger = Messenger::new(
         Arc::clone(&wallet),
-        StaticSecret::from(messenger_secret_key),
+        parse_messenger_secret_key(&env.messenger_secret_key)?,
         &env.key_registry_account_id,
         &env.message_repository_account_id,
-    ));
+    );
+    if env.double_ratchet {
+        messenger = messenger.with_double_ratchet();
+    }
+    let messenger = Arc::new(messenger);
+
+    if let Some(path) = env.trust_store_path.as_deref() {
+        messenger.import_trust_store(load_trust_store(path)?).await;
+    }
+
+    if let Some(path) = env.session_store_path.as_deref() {
+        messenger.import_sessions(load_session_store(path)?).await;
+    }
+
+    if let Some(path) = env.contact_store_path.as_deref() {
+        messenger.import_contacts(load_contact_store(path)?).await;
+    }
+
+    let mut key_changes = watch_key_changes(Arc::clone(&messenger));
 
     let stdout = console::Term::stdout();
 
@@ -132,6 +256,8 @@ async fn main() -> anyhow::Result<()> {
 
     write!(&stdout, "Syncing public key with key repository...").unwrap();
     messenger.sync_key().await?;
+    // The new prekeys have to be saved to answer sessions initiated with them.
+    save_session_store(env.session_store_path.as_deref(), &messenger).await?;
     writeln!(&stdout, "done.").unwrap();
 
     let mut line_editor = LineEditor::new("");
@@ -145,6 +271,27 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
         writeln!(&stdout, "\r{} to exit.", highlight::text::command("/quit")).unwrap();
 
+        messenger.poll_inbox().await?;
+        save_contact_store(env.contact_store_path.as_deref(), &messenger).await?;
+        for request in messenger.pending_contact_requests().await {
+            writeln!(
+                &stdout,
+                "\r{}",
+                highlight::text::control(format!(
+                    "{} wants to talk: {}",
+                    request.account_id, request.message,
+                )),
+            )
+            .unwrap();
+        }
+        writeln!(
+            &stdout,
+            "\rEnter an account to chat (which accepts their request), or {} / {} an account.",
+            highlight::text::command("/block"),
+            highlight::text::command("/unblock"),
+        )
+        .unwrap();
+
         line_editor.set_prompt("Chat with: ");
         line_editor.redraw_prompt();
         let correspondent: AccountId = loop {
@@ -152,24 +299,88 @@ async fn main() -> anyhow::Result<()> {
             if input == "/quit" || input == "/exit" {
                 return Ok(());
             }
-            if let Ok(account_id) = input.parse() {
-                break account_id;
+            let (command, tail) = input.split_once(' ').unwrap_or((&input, ""));
+            match (command, tail.parse::<AccountId>()) {
+                ("/block", Ok(account_id)) => {
+                    messenger.block_contact(&account_id).await;
+                    save_contact_store(env.contact_store_path.as_deref(), &messenger).await?;
+                    writeln!(
+                        &stdout,
+                        "\r{}",
+                        highlight::text::control(format!("Blocked {account_id}"))
+                    )
+                    .unwrap();
+                }
+                ("/unblock", Ok(account_id)) => {
+                    if messenger.unblock_contact(&account_id).await {
+                        save_contact_store(env.contact_store_path.as_deref(), &messenger).await?;
+                        writeln!(
+                            &stdout,
+                            "\r{}",
+                            highlight::text::control(format!("Unblocked {account_id}"))
+                        )
+                        .unwrap();
+                    }
+                }
+                _ => {
+                    if let Ok(account_id) = input.parse() {
+                        break account_id;
+                    }
+                }
             }
+            line_editor.redraw_prompt();
         };
 
         writeln!(
             &stdout,
-            "{} to say, {} to leave.",
+            "{} to say, {} to leave, {} to compare keys, {} for disappearing messages, {} to send a first contact.",
             highlight::text::command("/say"),
             highlight::text::command("/leave"),
+            highlight::text::command("/safety"),
+            highlight::text::command("/disappear"),
+            highlight::text::command("/introduce"),
         )
         .unwrap();
 
-        let group = Arc::new(messenger.direct_message(&correspondent).await.unwrap());
+        let conversation = match messenger.conversation(&correspondent).await {
+            Ok(conversation) => conversation,
+            Err(e) => {
+                writeln!(&stdout, "\r{}", highlight::text::error(e)).unwrap();
+                continue;
+            }
+        };
+        save_trust_store(env.trust_store_path.as_deref(), &messenger).await?;
+        save_contact_store(env.contact_store_path.as_deref(), &messenger).await?;
+        save_session_store(env.session_store_path.as_deref(), &messenger).await?;
 
-        let group_sender = ChunkedWriteStream::new(Arc::clone(&group), 32);
+        if messenger.key_agreement(&correspondent).await == Some(KeyAgreement::Static) {
+            writeln!(
+                &stdout,
+                "\r{}",
+                highlight::text::error(format!(
+                    "{correspondent} has not published prekeys, so this chat uses their long-term key only and has no forward secrecy."
+                )),
+            )
+            .unwrap();
+        }
 
-        let (kill, mut recv) = monitor_conversation(group.read_stream());
+        if !messenger.is_contact_verified(&correspondent).await {
+            writeln!(
+                &stdout,
+                "\r{}",
+                highlight::text::dim(format!(
+                    "{correspondent} is not verified. Compare safety numbers with {} and then {}.",
+                    highlight::text::command("/safety"),
+                    highlight::text::command("/verify"),
+                )),
+            )
+            .unwrap();
+        }
+
+        let channel = conversation.current_channel();
+        let group_sender = ChunkedWriteStream::new(Arc::clone(&channel), 32);
+
+        let (kill, mut recv) = monitor_conversation(conversation);
 
         line_editor.set_prompt(format!("{}> ", highlight::account::me(&wallet.account_id)));
 
@@ -190,10 +401,43 @@ async fn main() -> anyhow::Result<()> {
                     match command {
                         "/say" => {
                             group_sender.send(tail).await.unwrap();
+                            save_session_store(env.session_store_path.as_deref(), &messenger).await?;
+                        }
+                        "/safety" => {
+                            let safety_number = messenger.safety_number(&correspondent).await.unwrap_or_default();
+                            writeln!(&stdout, "\rSafety number with {}: {safety_number}", highlight::account::other(&correspondent)).unwrap();
+                        }
+                        "/verify" => {
+                            messenger.verify_contact(&correspondent).await;
+                            save_trust_store(env.trust_store_path.as_deref(), &messenger).await?;
+                            writeln!(&stdout, "\r{}", highlight::text::control(format!("Marked {correspondent} as verified"))).unwrap();
+                        }
+                        "/disappear" => {
+                            let retention_ms = match tail {
+                                "off" => Some(None),
+                                seconds => seconds.parse::<u64>().ok().filter(|s| *s > 0).and_then(|s| s.checked_mul(1000)).map(Some),
+                            };
+                            let Some(retention_ms) = retention_ms else {
+                                writeln!(&stdout, "\r{}", highlight::text::error("Usage: /disappear <seconds|off>")).unwrap();
+                                continue;
+                            };
+                            if let Err(e) = channel.set_retention(retention_ms).await {
+                                writeln!(&stdout, "\r{}", highlight::text::error(e)).unwrap();
+                                continue;
+                            }
+                            group_sender.send(Structured::DisappearingMessages(retention_ms).to_bytes()).await.unwrap();
+                            save_session_store(env.session_store_path.as_deref(), &messenger).await?;
+                        }
+                        "/introduce" => {
+                            match messenger.send_first_contact(&correspondent, tail).await {
+                                Ok(()) => writeln!(&stdout, "\r{}", highlight::text::control(format!("Sent a first contact to {correspondent}"))).unwrap(),
+                                Err(e) => writeln!(&stdout, "\r{}", highlight::text::error(e)).unwrap(),
+                            }
                         }
                         "/leave" => {
                             writeln!(&stdout, "\r{}.", highlight::text::control("Exiting chat")).unwrap();
                             kill();
+                            save_session_store(env.session_store_path.as_deref(), &messenger).await?;
                             break;
                         }
                         _ => {
@@ -201,15 +445,52 @@ async fn main() -> anyhow::Result<()> {
                         }
                     }
                 },
+                Some(key_change) = key_changes.recv() => {
+                    save_trust_store(env.trust_store_path.as_deref(), &messenger).await?;
+                    let warning = if key_change.was_verified {
+                        format!("The verified key of {} has changed! Compare safety numbers again before trusting new messages.", key_change.account_id)
+                    } else {
+                        format!("The key of {} has changed.", key_change.account_id)
+                    };
+                    writeln!(&stdout, "\r{}", highlight::text::error(warning)).unwrap();
+                },
                 recv_message = recv.recv() => {
                     if let Some((sender_id, recv_message)) = recv_message {
-                        let sender_id = messenger.resolve_correspondent_id(&sender_id).await.unwrap();
-                        let sender_styled = if sender_id == wallet.account_id {
+                        save_session_store(env.session_store_path.as_deref(), &messenger).await?;
+                        let sender_id = messenger.resolve_correspondent_id(&sender_id).await;
+                        let sender_styled = if sender_id.account_id() == Some(&wallet.account_id) {
                             highlight::account::me(&sender_id)
                         } else {
                             highlight::account::other(&sender_id)
                         };
                         let time_styled = highlight::text::dim(format_time(recv_message.block_timestamp_ms as i64));
+                        // Conversations drop moves that are not signed by the sender's bound signing key.
+                        if let Some(Structured::ChannelMoved(_)) = Structured::try_from_bytes(&recv_message.bytes) {
+                            writeln!(&stdout, "\r[{time_styled}] {}", highlight::text::control(format!("{sender_id} moved to a new key"))).unwrap();
+                            continue;
+                        }
+                        if let Some(Structured::DisappearingMessages(retention_ms)) = Structured::try_from_bytes(&recv_message.bytes) {
+                            let setting = match retention_ms {
+                                Some(retention_ms) => format!("{sender_id} set unread messages to disappear after {} seconds", retention_ms / 1000),
+                                None => format!("{sender_id} turned off disappearing messages"),
+                            };
+                            writeln!(&stdout, "\r[{time_styled}] {}", highlight::text::control(setting)).unwrap();
+                            continue;
+                        }
+                        if let Some(Structured::GroupInvite(invite)) = Structured::try_from_bytes(&recv_message.bytes) {
+                            let notice = if invite.generation == 0 {
+                                format!("{sender_id} invited you to the group {}", invite.name)
+                            } else {
+                                format!("{sender_id} changed the group {}", invite.name)
+                            };
+                            writeln!(&stdout, "\r[{time_styled}] {}", highlight::text::control(notice)).unwrap();
+                            continue;
+                        }
+                        if let Some(Structured::TreeWelcome(welcome)) = Structured::try_from_bytes(&recv_message.bytes) {
+                            let notice = format!("{sender_id} added you to the group {}", welcome.name);
+                            writeln!(&stdout, "\r[{time_styled}] {}", highlight::text::control(notice)).unwrap();
+                            continue;
+                        }
                         let message_string = String::from_utf8_lossy(&recv_message.bytes);
                         writeln!(&stdout, "\r[{time_styled}] {sender_styled}: {message_string}").unwrap();
                     } else {
@@ -2,12 +2,175 @@ use std::ops::Deref;
 
 use anyhow::bail;
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce,
 };
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-pub type SequenceNumber = u32;
+use crate::secret::Secret;
+
+pub type SequenceNumber = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChannelVersion {
+    /// The shared secret is used directly as the encryption key and as part
+    /// of the sequence hash preimage.
+    V0,
+    /// Separate subkeys are derived from the shared secret with HKDF, and
+    /// sequence hashes are keyed with HMAC.
+    V1,
+    /// Like V1, but the identifier also commits to the repository, the
+    /// network and the protocol version.
+    V2,
+    /// Like V2, but ciphertexts are bound to their sequence hash with AEAD
+    /// associated data.
+    V3,
+    /// Like V3, but with 64-bit sequence numbers and XChaCha20-Poly1305.
+    V4,
+}
+
+impl ChannelVersion {
+    pub const LATEST: Self = Self::V4;
+    pub const SUPPORTED: &'static [Self] = &[Self::V0, Self::V1, Self::V2, Self::V3, Self::V4];
+
+    /// Sequence numbers past this would repeat a nonce, so channels refuse
+    /// to use them.
+    pub fn max_sequence_number(self) -> SequenceNumber {
+        match self {
+            Self::V0 | Self::V1 | Self::V2 | Self::V3 => u32::MAX as SequenceNumber,
+            Self::V4 => SequenceNumber::MAX,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Self::V0 => 0,
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+            Self::V4 => 4,
+        }
+    }
+
+    pub fn from_u32(version: u32) -> Option<Self> {
+        match version {
+            0 => Some(Self::V0),
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            4 => Some(Self::V4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChannelFeature {
+    PaddingBuckets,
+    FilterSync,
+    Ratchet,
+    DoubleRatchet,
+    SenderAuthentication,
+    KeyCommitment,
+}
+
+impl ChannelFeature {
+    pub const SUPPORTED: &'static [Self] = &[
+        Self::Ratchet,
+        Self::DoubleRatchet,
+        Self::SenderAuthentication,
+        Self::KeyCommitment,
+    ];
+
+    /// Supported, but only advertised if the client enables them.
+    pub const OPT_IN: &'static [Self] = &[Self::DoubleRatchet];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PaddingBuckets => "padding_buckets",
+            Self::FilterSync => "filter_sync",
+            Self::Ratchet => "ratchet",
+            Self::DoubleRatchet => "double_ratchet",
+            Self::SenderAuthentication => "sender_authentication",
+            Self::KeyCommitment => "key_commitment",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "padding_buckets" => Some(Self::PaddingBuckets),
+            "filter_sync" => Some(Self::FilterSync),
+            "ratchet" => Some(Self::Ratchet),
+            "double_ratchet" => Some(Self::DoubleRatchet),
+            "sender_authentication" => Some(Self::SenderAuthentication),
+            "key_commitment" => Some(Self::KeyCommitment),
+            _ => None,
+        }
+    }
+}
+
+/// The channel format and optional features that all members of a channel
+/// have agreed on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelParameters {
+    pub version: ChannelVersion,
+    pub features: Vec<ChannelFeature>,
+}
+
+impl Default for ChannelParameters {
+    fn default() -> Self {
+        Self {
+            version: ChannelVersion::V0,
+            features: vec![],
+        }
+    }
+}
+
+impl ChannelParameters {
+    pub fn has_feature(&self, feature: ChannelFeature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// Subkeys derived from a channel's shared secret.
+#[derive(Debug)]
+pub struct ChannelKeys {
+    pub encryption: Secret,
+    pub identifier: Secret,
+    /// Reserved for features that need key material of their own.
+    pub auxiliary: Secret,
+}
+
+impl ChannelKeys {
+    pub fn derive(version: ChannelVersion, shared_secret: &Secret) -> Self {
+        match version {
+            ChannelVersion::V0 => Self {
+                encryption: shared_secret.clone(),
+                identifier: shared_secret.clone(),
+                auxiliary: shared_secret.clone(),
+            },
+            ChannelVersion::V1 | ChannelVersion::V2 | ChannelVersion::V3 | ChannelVersion::V4 => {
+                let salt = format!("fchan v{}", version.to_u32());
+                let hkdf = Hkdf::<Sha256>::new(Some(salt.as_bytes()), shared_secret.expose());
+                let expand = |label: &[u8]| {
+                    let mut key = [0u8; 32];
+                    // unwrap ok because 32 bytes is a valid output length
+                    hkdf.expand(label, &mut key).unwrap();
+                    Secret::from(key)
+                };
+                Self {
+                    encryption: expand(b"encryption"),
+                    identifier: expand(b"identifier"),
+                    auxiliary: expand(b"auxiliary"),
+                }
+            }
+        }
+    }
+}
 
 macro_rules! thin_marker {
     ($name: ident, $target: ty, $as_ref: ty) => {
@@ -41,34 +204,188 @@ macro_rules! thin_marker {
 thin_marker!(CorrespondentId, [u8; 32], [u8]);
 thin_marker!(SequenceHash, [u8; 32], [u8]);
 
+/// Legacy channels hash and encrypt with 32-bit sequence numbers.
+fn legacy_sequence_number(sequence_number: SequenceNumber) -> anyhow::Result<u32> {
+    match u32::try_from(sequence_number) {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("Sequence number {sequence_number} is out of range for this channel"),
+    }
+}
+
 fn u32_to_nonce(u: u32) -> Nonce {
     Nonce::from_exact_iter([u.to_le_bytes(), [0u8; 4], [0u8; 4]].concat()).unwrap()
 }
 
+/// The sequence number alone makes the nonce unique. The prefix taken from
+/// the sequence hash gives every message its own subkey.
+fn extended_nonce(sequence_number: SequenceNumber, sequence_hash: &SequenceHash) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..16].copy_from_slice(&sequence_hash[..16]);
+    nonce[16..].copy_from_slice(&sequence_number.to_le_bytes());
+    nonce
+}
+
+fn hmac(key: &[u8; 32], label: &[u8], input: &[u8]) -> [u8; 32] {
+    // unwrap ok because HMAC accepts keys of any length
+    <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .unwrap()
+        .chain_update(label)
+        .chain_update(input)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+const KEY_COMMITMENT_LENGTH: usize = 32;
+
+/// Prepended to key-committing ciphertexts. Finding a second key with the
+/// same commitment means finding an HMAC-SHA256 collision.
+fn key_commitment(key: &[u8; 32], sequence_hash: &SequenceHash) -> [u8; 32] {
+    hmac(key, b"fchan key commitment", sequence_hash.as_ref())
+}
+
+/// Key-committing ciphertexts are encrypted with a key derived from the
+/// message key, so that the commitment and the AEAD never share a key.
+fn committed_encryption_key(key: &[u8; 32]) -> [u8; 32] {
+    hmac(key, b"fchan committed encryption", &[])
+}
+
 pub trait Channel {
-    fn encrypt(&self, nonce: u32, message: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let cipher = ChaCha20Poly1305::new_from_slice(self.shared_secret())?;
-        let nonce = u32_to_nonce(nonce);
-        let ciphertext = match cipher.encrypt(&nonce, message) {
-            Ok(c) => c,
-            Err(e) => bail!(e),
-        };
-        Ok(ciphertext)
+    /// Whether ciphertexts commit to the key they were encrypted with.
+    /// ChaCha20-Poly1305 alone does not, so a crafted ciphertext can decrypt
+    /// to different messages under different keys.
+    fn key_committing(&self) -> bool {
+        false
     }
 
-    fn decrypt(&self, nonce: u32, message: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let cipher = ChaCha20Poly1305::new_from_slice(self.shared_secret())?;
-        let nonce = u32_to_nonce(nonce);
-        let cleartext = match cipher.decrypt(&nonce, message) {
-            Ok(c) => c,
+    /// Authenticated along with every message. Empty before V3.
+    fn associated_data(&self, sequence_hash: &SequenceHash) -> Vec<u8> {
+        match self.version() {
+            ChannelVersion::V0 | ChannelVersion::V1 | ChannelVersion::V2 => vec![],
+            version => {
+                let mut buf = Vec::with_capacity(8 + 4 + 32);
+                buf.extend(b"fchan ad");
+                buf.extend(version.to_u32().to_le_bytes());
+                buf.extend(sequence_hash.as_ref());
+                buf
+            }
+        }
+    }
+
+    fn encrypt(
+        &self,
+        nonce: SequenceNumber,
+        sequence_hash: &SequenceHash,
+        message: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.encrypt_with_key(
+            self.keys().encryption.expose(),
+            nonce,
+            sequence_hash,
+            message,
+        )
+    }
+
+    fn decrypt(
+        &self,
+        nonce: SequenceNumber,
+        sequence_hash: &SequenceHash,
+        message: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_with_key(
+            self.keys().encryption.expose(),
+            nonce,
+            sequence_hash,
+            message,
+        )
+    }
+
+    /// Like [`Channel::encrypt`], but with a per-message key instead of the
+    /// channel's encryption key.
+    fn encrypt_with_key(
+        &self,
+        key: &[u8; 32],
+        nonce: SequenceNumber,
+        sequence_hash: &SequenceHash,
+        message: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (mut buf, key) = match self.key_committing() {
+            true => (
+                key_commitment(key, sequence_hash).to_vec(),
+                Zeroizing::new(committed_encryption_key(key)),
+            ),
+            false => (vec![], Zeroizing::new(*key)),
+        };
+
+        let aad = self.associated_data(sequence_hash);
+        let payload = Payload {
+            msg: message,
+            aad: &aad,
+        };
+        let ciphertext = match self.version() {
+            ChannelVersion::V4 => XChaCha20Poly1305::new_from_slice(key.as_slice())?
+                .encrypt(&extended_nonce(nonce, sequence_hash), payload),
+            _ => ChaCha20Poly1305::new_from_slice(key.as_slice())?
+                .encrypt(&u32_to_nonce(legacy_sequence_number(nonce)?), payload),
+        };
+        match ciphertext {
+            Ok(c) => {
+                buf.extend(c);
+                Ok(buf)
+            }
             Err(e) => bail!(e),
+        }
+    }
+
+    fn decrypt_with_key(
+        &self,
+        key: &[u8; 32],
+        nonce: SequenceNumber,
+        sequence_hash: &SequenceHash,
+        message: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (message, key) = match self.key_committing() {
+            true => {
+                if message.len() < KEY_COMMITMENT_LENGTH {
+                    bail!("Ciphertext too short for a key commitment");
+                }
+                let (commitment, message) = message.split_at(KEY_COMMITMENT_LENGTH);
+                // unwrap ok because HMAC accepts keys of any length
+                let verified = <Hmac<Sha256> as Mac>::new_from_slice(key)
+                    .unwrap()
+                    .chain_update(b"fchan key commitment")
+                    .chain_update(sequence_hash.as_ref())
+                    .verify_slice(commitment);
+                if verified.is_err() {
+                    bail!("Ciphertext commits to a different key");
+                }
+                (message, Zeroizing::new(committed_encryption_key(key)))
+            }
+            false => (message, Zeroizing::new(*key)),
         };
-        Ok(cleartext)
+
+        let aad = self.associated_data(sequence_hash);
+        let payload = Payload {
+            msg: message,
+            aad: &aad,
+        };
+        let cleartext = match self.version() {
+            ChannelVersion::V4 => XChaCha20Poly1305::new_from_slice(key.as_slice())?
+                .decrypt(&extended_nonce(nonce, sequence_hash), payload),
+            _ => ChaCha20Poly1305::new_from_slice(key.as_slice())?
+                .decrypt(&u32_to_nonce(legacy_sequence_number(nonce)?), payload),
+        };
+        match cleartext {
+            Ok(c) => Ok(c),
+            Err(e) => bail!(e),
+        }
     }
 
+    fn version(&self) -> ChannelVersion;
+
     fn secret_identifier(&self) -> &[u8; 256];
 
-    fn shared_secret(&self) -> &[u8; 32];
+    fn keys(&self) -> &ChannelKeys;
 }
 
 pub trait SequenceHashProducer {
@@ -77,12 +394,155 @@ pub trait SequenceHashProducer {
 
 impl<T: Channel> SequenceHashProducer for T {
     fn sequence_hash(&self, sequence_number: SequenceNumber) -> SequenceHash {
-        let hash_bytes: [u8; 32] = <Sha256 as Digest>::new()
-            .chain_update(sequence_number.to_le_bytes())
-            .chain_update(self.secret_identifier())
-            .finalize()
-            .into();
+        // Legacy channels never reach sequence numbers past u32::MAX (see
+        // ChannelVersion::max_sequence_number), so the truncation is exact.
+        let encoded_sequence_number = match self.version() {
+            ChannelVersion::V4 => sequence_number.to_le_bytes().to_vec(),
+            _ => (sequence_number as u32).to_le_bytes().to_vec(),
+        };
+
+        let hash_bytes: [u8; 32] = match self.version() {
+            ChannelVersion::V0 => <Sha256 as Digest>::new()
+                .chain_update(encoded_sequence_number)
+                .chain_update(self.secret_identifier())
+                .finalize()
+                .into(),
+            ChannelVersion::V1 | ChannelVersion::V2 | ChannelVersion::V3 | ChannelVersion::V4 => {
+                // unwrap ok because HMAC accepts keys of any length
+                <Hmac<Sha256> as Mac>::new_from_slice(self.keys().identifier.expose())
+                    .unwrap()
+                    .chain_update(encoded_sequence_number)
+                    .chain_update(self.secret_identifier())
+                    .finalize()
+                    .into_bytes()
+                    .into()
+            }
+        };
 
         SequenceHash(hash_bytes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_subkeys_are_separated() {
+        let secret = Secret::from([3u8; 32]);
+
+        let v0 = ChannelKeys::derive(ChannelVersion::V0, &secret);
+        assert_eq!(v0.encryption.expose(), secret.expose());
+        assert_eq!(v0.identifier.expose(), secret.expose());
+
+        let v1 = ChannelKeys::derive(ChannelVersion::V1, &secret);
+        assert_ne!(v1.encryption.expose(), secret.expose());
+        assert_ne!(v1.encryption.expose(), v1.identifier.expose());
+        assert_ne!(v1.encryption.expose(), v1.auxiliary.expose());
+        assert_ne!(v1.identifier.expose(), v1.auxiliary.expose());
+    }
+
+    struct TestChannel {
+        version: ChannelVersion,
+        keys: ChannelKeys,
+        key_committing: bool,
+    }
+
+    impl Channel for TestChannel {
+        fn key_committing(&self) -> bool {
+            self.key_committing
+        }
+
+        fn version(&self) -> ChannelVersion {
+            self.version
+        }
+
+        fn secret_identifier(&self) -> &[u8; 256] {
+            &[0; 256]
+        }
+
+        fn keys(&self) -> &ChannelKeys {
+            &self.keys
+        }
+    }
+
+    #[test]
+    fn v3_ciphertext_is_bound_to_its_slot() {
+        let channel = TestChannel {
+            version: ChannelVersion::V3,
+            keys: ChannelKeys::derive(ChannelVersion::V3, &Secret::from([3; 32])),
+            key_committing: false,
+        };
+        let slot = channel.sequence_hash(0);
+        let other_slot = channel.sequence_hash(1);
+
+        let ciphertext = channel.encrypt(0, &slot, b"hello").unwrap();
+        assert_eq!(channel.decrypt(0, &slot, &ciphertext).unwrap(), b"hello");
+        assert!(channel.decrypt(0, &other_slot, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn legacy_channels_refuse_wide_sequence_numbers() {
+        let big = u32::MAX as SequenceNumber + 1;
+
+        let legacy = TestChannel {
+            version: ChannelVersion::V3,
+            keys: ChannelKeys::derive(ChannelVersion::V3, &Secret::from([3; 32])),
+            key_committing: false,
+        };
+        assert!(legacy
+            .encrypt(big, &legacy.sequence_hash(big), b"x")
+            .is_err());
+
+        let wide = TestChannel {
+            version: ChannelVersion::V4,
+            keys: ChannelKeys::derive(ChannelVersion::V4, &Secret::from([3; 32])),
+            key_committing: false,
+        };
+        let slot = wide.sequence_hash(big);
+        let ciphertext = wide.encrypt(big, &slot, b"x").unwrap();
+        assert_eq!(wide.decrypt(big, &slot, &ciphertext).unwrap(), b"x");
+        assert_ne!(slot, wide.sequence_hash(0));
+    }
+
+    #[test]
+    fn key_commitment_rejects_other_keys() {
+        let channel = TestChannel {
+            version: ChannelVersion::V3,
+            keys: ChannelKeys::derive(ChannelVersion::V3, &Secret::from([3; 32])),
+            key_committing: true,
+        };
+        let slot = channel.sequence_hash(0);
+        let (key_1, key_2) = ([1; 32], [2; 32]);
+
+        let ciphertext = channel
+            .encrypt_with_key(&key_1, 0, &slot, b"hello")
+            .unwrap();
+        assert_eq!(
+            channel
+                .decrypt_with_key(&key_1, 0, &slot, &ciphertext)
+                .unwrap(),
+            b"hello"
+        );
+        assert!(channel
+            .decrypt_with_key(&key_2, 0, &slot, &ciphertext)
+            .is_err());
+
+        // A ciphertext that the AEAD would accept under the second key is
+        // still refused under it, since it commits to the first one.
+        let mut forged = key_commitment(&key_1, &slot).to_vec();
+        forged.extend(
+            &channel
+                .encrypt_with_key(&key_2, 0, &slot, b"other")
+                .unwrap()[KEY_COMMITMENT_LENGTH..],
+        );
+        let error = channel
+            .decrypt_with_key(&key_2, 0, &slot, &forged)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Ciphertext commits to a different key");
+        assert!(channel.decrypt_with_key(&key_1, 0, &slot, &forged).is_err());
+
+        let ciphertext = channel.encrypt(0, &slot, b"hello").unwrap();
+        assert_eq!(channel.decrypt(0, &slot, &ciphertext).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use near_primitives::types::AccountId;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::PublicKey;
+
+use crate::trust;
+
+/// How many requests a sender may leave in our inbox per window before the
+/// rest are dropped unread.
+pub const MAX_REQUESTS_PER_WINDOW: usize = 3;
+pub const REQUEST_WINDOW_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Who is on the other side of a channel. Not every correspondent has an
+/// account in the key registry, e.g. one who answered an invite, so some are
+/// known only by their key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Contact {
+    Account(AccountId),
+    Key([u8; 32]),
+}
+
+impl Contact {
+    pub fn account_id(&self) -> Option<&AccountId> {
+        match self {
+            Self::Account(account_id) => Some(account_id),
+            Self::Key(_) => None,
+        }
+    }
+}
+
+/// Keys are shown by their fingerprint.
+impl fmt::Display for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Account(account_id) => write!(f, "{account_id}"),
+            Self::Key(public_key) => {
+                write!(
+                    f,
+                    "key {}",
+                    trust::fingerprint(&PublicKey::from(*public_key))
+                )
+            }
+        }
+    }
+}
+
+/// A first contact from someone we have not accepted yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContactRequest {
+    pub account_id: AccountId,
+    pub public_key: [u8; 32],
+    pub message: String,
+    pub block_timestamp_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactStatus {
+    Unknown,
+    Pending,
+    Accepted,
+    Blocked,
+}
+
+/// Who may reach us: pending requests from the inbox, the contacts we have
+/// accepted, and the accounts and keys we have blocked.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContactStore {
+    pending: Vec<ContactRequest>,
+    accepted: HashSet<AccountId>,
+    /// Blocked accounts, with every key they were seen with.
+    blocked: HashMap<AccountId, Vec<[u8; 32]>>,
+    /// Timestamps of the requests recently received from each sender.
+    recent_requests: HashMap<AccountId, Vec<u64>>,
+    /// Index of the next inbox slot to read.
+    inbox_cursor: u64,
+}
+
+impl ContactStore {
+    pub fn status(&self, account_id: &AccountId) -> ContactStatus {
+        if self.blocked.contains_key(account_id) {
+            ContactStatus::Blocked
+        } else if self.accepted.contains(account_id) {
+            ContactStatus::Accepted
+        } else if self.pending.iter().any(|r| &r.account_id == account_id) {
+            ContactStatus::Pending
+        } else {
+            ContactStatus::Unknown
+        }
+    }
+
+    pub fn is_key_blocked(&self, public_key: &[u8; 32]) -> bool {
+        self.blocked.values().flatten().any(|k| k == public_key)
+    }
+
+    pub fn pending(&self) -> &[ContactRequest] {
+        &self.pending
+    }
+
+    pub fn accepted(&self) -> impl Iterator<Item = &AccountId> {
+        self.accepted.iter()
+    }
+
+    pub fn blocked(&self) -> impl Iterator<Item = &AccountId> {
+        self.blocked.keys()
+    }
+
+    /// Records a request, replacing any earlier one from the same sender.
+    /// Returns `false` if it was dropped because the sender is blocked,
+    /// already accepted, or over the rate limit.
+    pub fn receive(&mut self, request: ContactRequest) -> bool {
+        match self.status(&request.account_id) {
+            ContactStatus::Blocked | ContactStatus::Accepted => return false,
+            ContactStatus::Pending | ContactStatus::Unknown => {}
+        }
+        if self.is_key_blocked(&request.public_key) {
+            return false;
+        }
+
+        let now = request.block_timestamp_ms;
+        let recent = self
+            .recent_requests
+            .entry(request.account_id.clone())
+            .or_default();
+        recent.retain(|t| now.saturating_sub(*t) < REQUEST_WINDOW_MS);
+        if recent.len() >= MAX_REQUESTS_PER_WINDOW {
+            return false;
+        }
+        recent.push(now);
+
+        self.pending.retain(|r| r.account_id != request.account_id);
+        self.pending.push(request);
+        true
+    }
+
+    pub fn accept(&mut self, account_id: &AccountId) {
+        self.pending.retain(|r| &r.account_id != account_id);
+        self.recent_requests.remove(account_id);
+        self.blocked.remove(account_id);
+        self.accepted.insert(account_id.clone());
+    }
+
+    /// Blocks `account_id` and `public_keys`, along with the key of any
+    /// request it left.
+    pub fn block(&mut self, account_id: &AccountId, public_keys: &[[u8; 32]]) {
+        let keys = self.blocked.entry(account_id.clone()).or_default();
+        let request_keys = self
+            .pending
+            .iter()
+            .filter(|r| &r.account_id == account_id)
+            .map(|r| r.public_key);
+        for key in public_keys.iter().copied().chain(request_keys) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        self.pending.retain(|r| &r.account_id != account_id);
+        self.recent_requests.remove(account_id);
+        self.accepted.remove(account_id);
+    }
+
+    /// Returns `false` if `account_id` was not blocked.
+    pub fn unblock(&mut self, account_id: &AccountId) -> bool {
+        self.blocked.remove(account_id).is_some()
+    }
+
+    pub fn inbox_cursor(&self) -> u64 {
+        self.inbox_cursor
+    }
+
+    pub fn set_inbox_cursor(&mut self, inbox_cursor: u64) {
+        self.inbox_cursor = inbox_cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(account_id: &AccountId, block_timestamp_ms: u64) -> ContactRequest {
+        ContactRequest {
+            account_id: account_id.clone(),
+            public_key: [1; 32],
+            message: "hi".to_string(),
+            block_timestamp_ms,
+        }
+    }
+
+    #[test]
+    fn requests_are_rate_limited_per_sender() {
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        let mut store = ContactStore::default();
+
+        for t in 0..MAX_REQUESTS_PER_WINDOW as u64 {
+            assert!(store.receive(request(&alice, t)));
+        }
+        assert!(!store.receive(request(&alice, 10)));
+        assert!(store.receive(request(&bob, 10)));
+        assert!(store.receive(request(&alice, REQUEST_WINDOW_MS)));
+        // Only the latest request from each sender is kept.
+        assert_eq!(store.pending().len(), 2);
+    }
+
+    #[test]
+    fn blocking_drops_requests_and_keys() {
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let mallory: AccountId = "mallory.near".parse().unwrap();
+        let mut store = ContactStore::default();
+
+        assert!(store.receive(request(&mallory, 0)));
+        assert_eq!(store.status(&mallory), ContactStatus::Pending);
+        store.block(&mallory, &[]);
+        assert_eq!(store.status(&mallory), ContactStatus::Blocked);
+        assert!(store.pending().is_empty());
+        assert!(!store.receive(request(&mallory, 1)));
+
+        // The key of the blocked request stays blocked under another name.
+        assert!(!store.receive(request(&alice, 1)));
+
+        assert!(store.unblock(&mallory));
+        store.accept(&alice);
+        assert_eq!(store.status(&alice), ContactStatus::Accepted);
+        assert!(!store.receive(request(&alice, 2)));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::bail;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use tokio::sync::Mutex;
+use x25519_dalek::PublicKey;
+
+use crate::{
+    channel::CorrespondentId,
+    group::{Group, GroupReadStream},
+    message::{cleartext::CleartextMessage, stream::ReadStream, structured::Structured},
+};
+
+/// The last message a party posts to a direct channel before switching to a
+/// new key. Signed with the signing key of the key being retired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMoved {
+    pub previous_key: PublicKey,
+    pub new_key: PublicKey,
+    pub signing_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl ChannelMoved {
+    fn signed_bytes(previous_key: &PublicKey, new_key: &PublicKey) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(19 + 32 + 32);
+        buf.extend(b"fchan channel moved");
+        buf.extend(previous_key.as_bytes());
+        buf.extend(new_key.as_bytes());
+        buf
+    }
+
+    pub fn new(previous_key: &PublicKey, new_key: &PublicKey, signing_key: &SigningKey) -> Self {
+        Self {
+            previous_key: *previous_key,
+            new_key: *new_key,
+            signing_key: signing_key.verifying_key(),
+            signature: signing_key.sign(&Self::signed_bytes(previous_key, new_key)),
+        }
+    }
+
+    /// Checks the signature against `signing_key`, which has to be bound to
+    /// `previous_key` already, e.g. by the trust store. The signing key in
+    /// the message itself proves nothing.
+    pub fn verify(&self, signing_key: &VerifyingKey) -> anyhow::Result<()> {
+        if self.signing_key != *signing_key {
+            bail!("Channel move is not signed by the signing key of the previous key");
+        }
+        let bytes = Self::signed_bytes(&self.previous_key, &self.new_key);
+        if let Err(e) = self.signing_key.verify(&bytes, &self.signature) {
+            bail!("Invalid channel move signature: {e}");
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + 32 + 32 + 64);
+        buf.extend(self.previous_key.as_bytes());
+        buf.extend(self.new_key.as_bytes());
+        buf.extend(self.signing_key.as_bytes());
+        buf.extend(self.signature.to_bytes());
+        buf
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 32 + 32 + 32 + 64 {
+            return None;
+        }
+        let previous_key: [u8; 32] = bytes[0..32].try_into().ok()?;
+        let new_key: [u8; 32] = bytes[32..64].try_into().ok()?;
+        let signing_key = VerifyingKey::from_bytes(bytes[64..96].try_into().ok()?).ok()?;
+        let signature = Signature::from_bytes(bytes[96..160].try_into().ok()?);
+
+        Some(Self {
+            previous_key: previous_key.into(),
+            new_key: new_key.into(),
+            signing_key,
+            signature,
+        })
+    }
+}
+
+/// A direct conversation that spans every channel the two parties have used,
+/// oldest first. Read positions are kept per channel, so moving to a new
+/// channel does not repeat messages that were already read.
+pub struct Conversation {
+    channels: Vec<Arc<Group>>,
+    streams: Vec<GroupReadStream>,
+    current: Mutex<usize>,
+    signing_keys: HashMap<CorrespondentId, VerifyingKey>,
+}
+
+impl Conversation {
+    /// # Panics
+    ///
+    /// Panics if `channels` is empty.
+    pub fn new(channels: Vec<Arc<Group>>) -> Self {
+        assert!(!channels.is_empty(), "A conversation needs a channel");
+        Self {
+            streams: channels.iter().map(Group::read_stream).collect(),
+            channels,
+            current: Mutex::new(0),
+            signing_keys: HashMap::new(),
+        }
+    }
+
+    /// Moves are only followed, and returned by the stream, if they are
+    /// signed with the signing key here for their previous key.
+    pub fn with_signing_keys(
+        mut self,
+        signing_keys: HashMap<CorrespondentId, VerifyingKey>,
+    ) -> Self {
+        self.signing_keys = signing_keys;
+        self
+    }
+
+    /// The newest channel, to which new messages should be sent.
+    pub fn current_channel(&self) -> Arc<Group> {
+        // unwrap ok because the constructor rejects empty lists
+        Arc::clone(self.channels.last().unwrap())
+    }
+
+    fn is_verified(&self, sender: &CorrespondentId, moved: &ChannelMoved) -> bool {
+        let previous_id: CorrespondentId = moved.previous_key.to_bytes().into();
+        *sender == previous_id
+            && self
+                .signing_keys
+                .get(&previous_id)
+                .is_some_and(|signing_key| moved.verify(signing_key).is_ok())
+    }
+
+    /// Index of the channel to which `moved` points.
+    fn moved_to(&self, current: usize, moved: &ChannelMoved) -> Option<usize> {
+        let new_id: CorrespondentId = moved.new_key.to_bytes().into();
+        (current + 1..self.channels.len())
+            .find(|&i| self.channels[i].get_correspondent_index(&new_id).is_some())
+    }
+}
+
+impl ReadStream for Conversation {
+    type Output = (CorrespondentId, CleartextMessage);
+
+    async fn receive_next(&self) -> anyhow::Result<Option<Self::Output>> {
+        let mut current = self.current.lock().await;
+
+        // Later channels are read as well in case a move was not announced
+        // (e.g. the old key was lost), but only a verified move retires the
+        // current channel. Unverified moves are dropped.
+        for i in *current..self.channels.len() {
+            while let Some((sender, message)) = self.streams[i].receive_next().await? {
+                if let Some(Structured::ChannelMoved(moved)) =
+                    Structured::try_from_bytes(&message.bytes)
+                {
+                    if !self.is_verified(&sender, &moved) {
+                        continue;
+                    }
+                    if let Some(next) = self.moved_to(i, &moved) {
+                        *current = next;
+                    }
+                }
+                if let Some(Structured::DisappearingMessages(retention_ms)) =
+                    Structured::try_from_bytes(&message.bytes)
+                {
+                    // Channels without ratchets have no keys to delete.
+                    let _ = self.channels[i].set_retention(retention_ms).await;
+                }
+                return Ok(Some((sender, message)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use x25519_dalek::StaticSecret;
+
+    use super::*;
+
+    #[test]
+    fn channel_moved_roundtrip_and_verify() {
+        let previous_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let new_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+
+        let moved = ChannelMoved::new(&previous_key, &new_key, &signing_key);
+        let decoded = ChannelMoved::try_from_bytes(&moved.to_bytes()).unwrap();
+        assert_eq!(decoded, moved);
+        assert!(decoded.verify(&signing_key.verifying_key()).is_ok());
+
+        let mut forged = moved;
+        forged.new_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        assert!(forged.verify(&signing_key.verifying_key()).is_err());
+
+        let other_signing_key = SigningKey::from_bytes(&[8; 32]);
+        let self_signed = ChannelMoved::new(&previous_key, &new_key, &other_signing_key);
+        assert!(self_signed.verify(&signing_key.verifying_key()).is_err());
+    }
+}
@@ -1,10 +1,30 @@
-use std::{borrow::Borrow, sync::Arc};
+use std::{
+    borrow::Borrow,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::bail;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
 use sha2::{Digest, Sha256};
 use tokio::sync::{Mutex, RwLock};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
 
 use crate::{
-    channel::{Channel, CorrespondentId, SequenceHashProducer},
+    channel::{
+        Channel, ChannelFeature, ChannelKeys, ChannelParameters, ChannelVersion, CorrespondentId,
+        SequenceHash, SequenceHashProducer, SequenceNumber,
+    },
     message::{
         chunk::ChunkedReadStream,
         cleartext::CleartextMessage,
@@ -12,25 +32,313 @@ use crate::{
         to_message_bytes::ToMessageBytes,
     },
     message_repository::MessageRepository,
+    ratchet::{DoubleRatchet, EpochChains, Header, SenderChains, SenderRatchet},
+    secret::Secret,
 };
 
+const SENDER_TAG_LENGTH: usize = 16;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Key shared by us and `member` for tags that prove which of the two wrote a
+/// message.
+fn sender_key(secret_key: &StaticSecret, member: &CorrespondentId) -> Secret {
+    let shared = secret_key.diffie_hellman(&PublicKey::from(**member));
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared.as_bytes())
+        .expand(b"fchan sender authentication", &mut key)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    Secret::from(key)
+}
+
+fn sender_tag_mac(key: &Secret, sequence_hash: &SequenceHash, ciphertext: &[u8]) -> Hmac<Sha256> {
+    // unwrap ok because HMAC accepts keys of any length
+    <Hmac<Sha256> as Mac>::new_from_slice(key.expose())
+        .unwrap()
+        .chain_update(sequence_hash.as_ref())
+        .chain_update(ciphertext)
+}
+
+/// Prepends one tag per member, in member order, each keyed with the key we
+/// share with that member.
+fn add_sender_tags(
+    sender_keys: &[Secret],
+    sequence_hash: &SequenceHash,
+    ciphertext: &[u8],
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(sender_keys.len() * SENDER_TAG_LENGTH + ciphertext.len());
+    for key in sender_keys {
+        let tag = sender_tag_mac(key, sequence_hash, ciphertext)
+            .finalize()
+            .into_bytes();
+        buf.extend(&tag[..SENDER_TAG_LENGTH]);
+    }
+    buf.extend(ciphertext);
+    buf
+}
+
+/// Checks the tag addressed to the member at `own_index`, using the key
+/// shared with the slot's owner, and returns the ciphertext.
+fn verify_sender_tag<'a>(
+    sender_key: &Secret,
+    own_index: usize,
+    member_count: usize,
+    sequence_hash: &SequenceHash,
+    message: &'a [u8],
+) -> Option<&'a [u8]> {
+    let ciphertext = message.get(member_count * SENDER_TAG_LENGTH..)?;
+    let tag = &message[own_index * SENDER_TAG_LENGTH..(own_index + 1) * SENDER_TAG_LENGTH];
+    sender_tag_mac(sender_key, sequence_hash, ciphertext)
+        .verify_truncated_left(tag)
+        .ok()?;
+    Some(ciphertext)
+}
+
+/// Each member's messages are read with their own ratchet. Our own messages
+/// are written with a separate one, because reading our history and writing
+/// new messages happen at different positions.
+struct Ratchets {
+    read: Mutex<Vec<SenderRatchet>>,
+    write: Mutex<SenderRatchet>,
+}
+
+impl Ratchets {
+    fn restore(chains: &EpochChains) -> Self {
+        Self {
+            read: Mutex::new(chains.read.clone()),
+            write: Mutex::new(chains.write.clone()),
+        }
+    }
+
+    async fn chains(&self) -> EpochChains {
+        EpochChains {
+            read: self.read.lock().await.clone(),
+            write: self.write.lock().await.clone(),
+        }
+    }
+}
+
+/// The keys of a channel between two rekeys. Epoch 0 is derived from the
+/// shared secret, and every later epoch from a secret published, encrypted,
+/// by a member during the epoch before it.
+struct Epoch {
+    version: ChannelVersion,
+    key_committing: bool,
+    identifier: Zeroizing<[u8; 256]>,
+    keys: ChannelKeys,
+    ratchets: Option<Ratchets>,
+    /// Rekey attempts before this one are known to hold entries that cannot
+    /// be opened.
+    rekey_attempt: AtomicU32,
+}
+
+impl Epoch {
+    fn new(
+        base_identifier: &[u8; 256],
+        number: u64,
+        secret: &Secret,
+        parameters: &ChannelParameters,
+        members: &[CorrespondentId],
+        send_messages_from_member_index: usize,
+    ) -> Self {
+        let mut identifier = Zeroizing::new(*base_identifier);
+        if parameters.version == ChannelVersion::V0 {
+            // Later versions key the sequence hash instead.
+            identifier[64..96].copy_from_slice(secret.expose());
+        }
+        // Zero in epoch 0, so channels that never rekey keep their slots.
+        identifier[196..204].copy_from_slice(&number.to_le_bytes());
+
+        let keys = ChannelKeys::derive(parameters.version, secret);
+
+        // Seeded with the epoch secret, which is not kept, so that the chains
+        // cannot be started over from the channel keys.
+        let ratchets = parameters
+            .has_feature(ChannelFeature::Ratchet)
+            .then(|| Ratchets {
+                read: Mutex::new(
+                    members
+                        .iter()
+                        .map(|m| SenderRatchet::new(secret.expose(), m))
+                        .collect(),
+                ),
+                write: Mutex::new(SenderRatchet::new(
+                    secret.expose(),
+                    &members[send_messages_from_member_index],
+                )),
+            });
+
+        Self {
+            version: parameters.version,
+            key_committing: parameters.has_feature(ChannelFeature::KeyCommitment),
+            identifier,
+            keys,
+            ratchets,
+            rekey_attempt: AtomicU32::new(0),
+        }
+    }
+
+    /// Where the secret of the next epoch is published. Slots can only be
+    /// written once, so concurrent rekeys resolve to the same epoch for
+    /// everyone. Anyone can write to a slot, so an entry that cannot be
+    /// opened moves the rekey to the slot of the next attempt.
+    fn rekey_slot(&self, attempt: u32) -> SequenceHash {
+        // Attempt 0 keeps the slot of channels from before attempts.
+        let attempt_bytes = match attempt {
+            0 => vec![],
+            _ => attempt.to_le_bytes().to_vec(),
+        };
+        let hash_bytes: [u8; 32] = match self.version {
+            ChannelVersion::V0 => <Sha256 as Digest>::new()
+                .chain_update(b"fchan rekey slot")
+                .chain_update(self.identifier.as_slice())
+                .chain_update(&attempt_bytes)
+                .finalize()
+                .into(),
+            _ => {
+                // unwrap ok because HMAC accepts keys of any length
+                <Hmac<Sha256> as Mac>::new_from_slice(self.keys.identifier.expose())
+                    .unwrap()
+                    .chain_update(b"fchan rekey slot")
+                    .chain_update(self.identifier.as_slice())
+                    .chain_update(&attempt_bytes)
+                    .finalize()
+                    .into_bytes()
+                    .into()
+            }
+        };
+        hash_bytes.into()
+    }
+
+    fn rekey_cipher(&self) -> XChaCha20Poly1305 {
+        // unwrap ok because HMAC accepts keys of any length
+        let key = <Hmac<Sha256> as Mac>::new_from_slice(self.keys.auxiliary.expose())
+            .unwrap()
+            .chain_update(b"fchan rekey")
+            .finalize()
+            .into_bytes();
+        XChaCha20Poly1305::new(&key)
+    }
+
+    fn rekey_associated_data(&self, next_number: u64, attempt: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(11 + 8 + 32);
+        buf.extend(b"fchan rekey");
+        buf.extend(next_number.to_le_bytes());
+        buf.extend(self.rekey_slot(attempt).as_ref());
+        buf
+    }
+
+    /// Encrypts the secret of epoch `next_number` to this epoch. The nonce is
+    /// random because racing members may seal under the same key, and losing
+    /// transactions are public too.
+    fn seal_next_secret(
+        &self,
+        next_number: u64,
+        attempt: u32,
+        secret: &Secret,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = XNonce::default();
+        OsRng.fill_bytes(&mut nonce);
+        let aad = self.rekey_associated_data(next_number, attempt);
+        let ciphertext = match self.rekey_cipher().encrypt(
+            &nonce,
+            Payload {
+                msg: secret.expose(),
+                aad: &aad,
+            },
+        ) {
+            Ok(c) => c,
+            Err(e) => bail!(e),
+        };
+
+        let mut buf = nonce.to_vec();
+        buf.extend(ciphertext);
+        Ok(buf)
+    }
+
+    fn open_next_secret(
+        &self,
+        next_number: u64,
+        attempt: u32,
+        sealed: &[u8],
+    ) -> anyhow::Result<Secret> {
+        if sealed.len() < 24 {
+            bail!("Rekey message too short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let aad = self.rekey_associated_data(next_number, attempt);
+        let secret = match self.rekey_cipher().decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        ) {
+            Ok(s) => Zeroizing::new(s),
+            Err(e) => bail!(e),
+        };
+
+        match <[u8; 32]>::try_from(secret.as_slice()) {
+            Ok(secret) => Ok(Secret::from(secret)),
+            Err(_) => bail!("Rekey message has the wrong length"),
+        }
+    }
+}
+
+impl Channel for Epoch {
+    fn key_committing(&self) -> bool {
+        self.key_committing
+    }
+
+    fn version(&self) -> ChannelVersion {
+        self.version
+    }
+
+    fn secret_identifier(&self) -> &[u8; 256] {
+        &self.identifier
+    }
+
+    fn keys(&self) -> &ChannelKeys {
+        &self.keys
+    }
+}
+
+pub type GroupReadStream = MultiplexedReadStream<ChunkedReadStream<GroupCorrespondentReadStream>>;
+
 pub struct Group {
     message_repository: Arc<MessageRepository>,
     send_messages_from_member_index: usize,
     members: Vec<CorrespondentId>,
-    next_message_read_index: RwLock<Vec<u32>>,
-    next_message_write_index: RwLock<Vec<u32>>,
-    shared_secret: [u8; 32],
+    next_message_read_index: RwLock<Vec<u64>>,
+    next_message_write_index: RwLock<Vec<u64>>,
+    /// The epoch each member is being read in.
+    read_epochs: RwLock<Vec<usize>>,
+    write_epoch: RwLock<usize>,
+    epochs: RwLock<Vec<Arc<Epoch>>>,
     identifier: [u8; 256],
+    parameters: ChannelParameters,
+    double_ratchet: Option<Arc<Mutex<DoubleRatchet>>>,
+    sender_chains: Option<Arc<Mutex<SenderChains>>>,
+    /// Retention of channels without a Double Ratchet, which keeps its own.
+    retention_ms: RwLock<Option<u64>>,
+    /// Keys shared with each member, in member order. Anyone with the channel
+    /// key can write to any slot, but only the slot's owner can tag for it.
+    sender_keys: Option<Vec<Secret>>,
 }
 
 impl Group {
     pub fn new(
         message_repository: Arc<MessageRepository>,
+        network: &str,
         send_messages_from_member: CorrespondentId,
         mut other_members: Vec<CorrespondentId>,
-        shared_secret: [u8; 32],
+        shared_secret: Secret,
         context: &[u8],
+        parameters: ChannelParameters,
     ) -> Self {
         other_members.push(send_messages_from_member.clone());
         let mut members = other_members;
@@ -50,25 +358,198 @@ impl Group {
 
         let mut identifier = [0u8; 256];
         identifier[0..32].copy_from_slice(&members_hash);
-        identifier[64..96].copy_from_slice(&shared_secret);
         identifier[96..128].copy_from_slice(&context_hash);
+        if parameters.version >= ChannelVersion::V2 {
+            let repository_hash = <Sha256 as Digest>::new()
+                .chain_update(message_repository.account_id().as_str())
+                .finalize();
+            let network_hash = <Sha256 as Digest>::new().chain_update(network).finalize();
+            identifier[128..160].copy_from_slice(&repository_hash);
+            identifier[160..192].copy_from_slice(&network_hash);
+            identifier[192..196].copy_from_slice(&parameters.version.to_u32().to_le_bytes());
+        }
 
         let nmi = members
             .iter()
             .enumerate()
-            .map(|(i, _)| i as u32)
+            .map(|(i, _)| i as u64)
             .collect::<Vec<_>>();
         let next_message_read_index = RwLock::new(nmi.clone());
         let next_message_send_index = RwLock::new(nmi);
 
+        let epoch = Epoch::new(
+            &identifier,
+            0,
+            &shared_secret,
+            &parameters,
+            &members,
+            send_messages_from_member_index,
+        );
+
         Self {
             message_repository,
+            read_epochs: RwLock::new(vec![0; members.len()]),
             members,
             send_messages_from_member_index,
             next_message_read_index,
             next_message_write_index: next_message_send_index,
-            shared_secret,
+            write_epoch: RwLock::new(0),
+            epochs: RwLock::new(vec![Arc::new(epoch)]),
             identifier,
+            parameters,
+            double_ratchet: None,
+            sender_chains: None,
+            retention_ms: RwLock::new(None),
+            sender_keys: None,
+        }
+    }
+
+    /// Tags every message for each member, and drops messages whose tag for
+    /// us does not match the member whose slot they are in. All members have
+    /// to enable this.
+    pub fn with_sender_authentication(mut self, secret_key: &StaticSecret) -> Self {
+        self.sender_keys = Some(
+            self.members
+                .iter()
+                .map(|m| sender_key(secret_key, m))
+                .collect(),
+        );
+        self
+    }
+
+    /// Encrypts with a Double Ratchet instead of the channel key. Only for
+    /// channels with two members. The channel continues from the cursors
+    /// stored in `state`, and keeps them up to date.
+    pub async fn with_double_ratchet(mut self, state: Arc<Mutex<DoubleRatchet>>) -> Self {
+        {
+            let state = state.lock().await;
+            if let Some(read_cursor) = &state.read_cursor {
+                if read_cursor.len() == self.members.len() {
+                    *self.next_message_read_index.get_mut() = read_cursor.clone();
+                }
+            }
+            if let Some(write_cursor) = &state.write_cursor {
+                if write_cursor.len() == self.members.len() {
+                    *self.next_message_write_index.get_mut() = write_cursor.clone();
+                }
+            }
+        }
+        self.double_ratchet = Some(state);
+        self
+    }
+
+    /// Continues the sender ratchets and read positions stored in `state`
+    /// instead of starting them over, and keeps them up to date. Only for
+    /// channels with [`ChannelFeature::Ratchet`].
+    pub async fn with_sender_chains(mut self, state: Arc<Mutex<SenderChains>>) -> Self {
+        {
+            let state = state.lock().await;
+            let members = self.members.len();
+            if let Some(chains) = state.epoch(0).filter(|c| c.read.len() == members) {
+                // unwrap ok because the group has not been shared yet
+                Arc::get_mut(&mut self.epochs.get_mut()[0])
+                    .unwrap()
+                    .ratchets = Some(Ratchets::restore(chains));
+            }
+            if let Some(read_epochs) = &state.read_epochs {
+                if read_epochs.len() == members {
+                    *self.read_epochs.get_mut() = read_epochs.clone();
+                }
+            }
+            if let Some(write_epoch) = state.write_epoch {
+                *self.write_epoch.get_mut() = write_epoch;
+            }
+            if let Some(read_cursor) = &state.read_cursor {
+                if read_cursor.len() == members {
+                    *self.next_message_read_index.get_mut() = read_cursor.clone();
+                }
+            }
+            if let Some(write_cursor) = &state.write_cursor {
+                if write_cursor.len() == members {
+                    *self.next_message_write_index.get_mut() = write_cursor.clone();
+                }
+            }
+            *self.retention_ms.get_mut() = state.retention_ms;
+        }
+        self.sender_chains = Some(state);
+        self
+    }
+
+    /// Stores the ratchets of epoch `number` and the positions in `state`.
+    async fn save_sender_chains(&self, number: usize, epoch: &Epoch) {
+        let (Some(state), Some(ratchets)) = (&self.sender_chains, &epoch.ratchets) else {
+            return;
+        };
+        let chains = ratchets.chains().await;
+        let read_epochs = self.read_epochs.read().await.clone();
+        let write_epoch = *self.write_epoch.read().await;
+        let read_cursor = self.next_message_read_index.read().await.clone();
+        let write_cursor = self.next_message_write_index.read().await.clone();
+
+        let mut state = state.lock().await;
+        state.set_epoch(number, chains);
+        state.read_epochs = Some(read_epochs);
+        state.write_epoch = Some(write_epoch);
+        state.read_cursor = Some(read_cursor);
+        state.write_cursor = Some(write_cursor);
+    }
+
+    pub fn parameters(&self) -> &ChannelParameters {
+        &self.parameters
+    }
+
+    /// Sets how long keys for unread messages are kept before they are
+    /// deleted, and deletes those that are already past it. `None` keeps them
+    /// until they are used. Messages that have been in the channel for longer
+    /// are skipped when read, which moves the ratchets past them. Only
+    /// channels with a Double Ratchet or sender ratchets have per-message
+    /// keys that can be deleted.
+    pub async fn set_retention(&self, retention_ms: Option<u64>) -> anyhow::Result<()> {
+        if let Some(state) = &self.double_ratchet {
+            let mut state = state.lock().await;
+            state.retention_ms = retention_ms;
+            state.shred(now_ms());
+            return Ok(());
+        }
+        if !self.parameters.has_feature(ChannelFeature::Ratchet) {
+            bail!("Disappearing messages need a ratcheted channel");
+        }
+        *self.retention_ms.write().await = retention_ms;
+        if let Some(state) = &self.sender_chains {
+            state.lock().await.retention_ms = retention_ms;
+        }
+        Ok(())
+    }
+
+    pub async fn retention(&self) -> Option<u64> {
+        match &self.double_ratchet {
+            Some(state) => state.lock().await.retention_ms,
+            None => *self.retention_ms.read().await,
+        }
+    }
+
+    /// Moves the ratchets past a message that has been kept for longer than
+    /// the retention period, without decrypting it.
+    async fn expire(&self, epoch: &Epoch, ci: usize, message_index: u64, message: &[u8]) {
+        match (&self.double_ratchet, &epoch.ratchets) {
+            (Some(state), _) => {
+                let mut state = state.lock().await;
+                if ci == self.send_messages_from_member_index {
+                    state.forget_sent_key(message_index);
+                } else if let Some((header, _)) = Header::split(message) {
+                    let mut next = state.clone();
+                    if next.receiving_key(&header, now_ms()).is_ok() {
+                        *state = next;
+                    }
+                }
+            }
+            (None, Some(ratchets)) => {
+                let mut read = ratchets.read.lock().await;
+                if let Ok((_, next)) = read[ci].message_key(message_index) {
+                    read[ci] = next;
+                }
+            }
+            (None, None) => {}
         }
     }
 
@@ -79,42 +560,291 @@ impl Group {
             .map(|i| i as u32)
     }
 
-    pub fn nonce_for_message(&self, message_index: u32, correspondent_index: u32) -> u32 {
-        self.members.len() as u32 * message_index + correspondent_index
+    /// Fails instead of wrapping around, which would reuse a nonce.
+    pub fn nonce_for_message(
+        &self,
+        message_index: u64,
+        correspondent_index: u32,
+    ) -> anyhow::Result<SequenceNumber> {
+        let nonce = (self.members.len() as SequenceNumber)
+            .checked_mul(message_index)
+            .and_then(|n| n.checked_add(correspondent_index as SequenceNumber))
+            .filter(|n| *n <= self.parameters.version.max_sequence_number());
+        match nonce {
+            Some(nonce) => Ok(nonce),
+            None => bail!("Channel has run out of sequence numbers"),
+        }
+    }
+
+    /// The newest epoch known so far.
+    pub async fn epoch(&self) -> u64 {
+        (self.epochs.read().await.len() - 1) as u64
+    }
+
+    /// Follows the rekeys published since the last call and returns the
+    /// newest epoch.
+    async fn sync_epochs(&self) -> anyhow::Result<usize> {
+        let mut epochs = self.epochs.write().await;
+        loop {
+            let next_number = epochs.len() as u64;
+            // unwrap ok because there is always epoch 0
+            let last = epochs.last().unwrap();
+            let Some(secret) = self.next_secret(last, next_number).await? else {
+                return Ok(epochs.len() - 1);
+            };
+            let mut epoch = Epoch::new(
+                &self.identifier,
+                next_number,
+                &secret,
+                &self.parameters,
+                &self.members,
+                self.send_messages_from_member_index,
+            );
+            if let Some(state) = &self.sender_chains {
+                if let Some(chains) = state.lock().await.epoch(epochs.len()) {
+                    epoch.ratchets = Some(Ratchets::restore(chains));
+                }
+            }
+            epochs.push(Arc::new(epoch));
+        }
+    }
+
+    /// The secret of the epoch after `epoch`, if one has been published.
+    /// Entries that cannot be opened are skipped, since any account can
+    /// write them.
+    async fn next_secret(&self, epoch: &Epoch, next_number: u64) -> anyhow::Result<Option<Secret>> {
+        loop {
+            let attempt = epoch.rekey_attempt.load(Ordering::Acquire);
+            let Some(sealed) = self
+                .message_repository
+                .get_message(&*epoch.rekey_slot(attempt))
+                .await?
+            else {
+                return Ok(None);
+            };
+            if let Ok(secret) = epoch.open_next_secret(next_number, attempt, &sealed.message) {
+                return Ok(Some(secret));
+            }
+            epoch.rekey_attempt.store(attempt + 1, Ordering::Release);
+        }
+    }
+
+    /// Starts a new epoch with a fresh secret, which is published encrypted
+    /// to the current one. Messages sent afterwards, by any member, can only
+    /// be read with the new secret. Returns the new epoch.
+    ///
+    /// Fails if another member rekeyed the same epoch first.
+    pub async fn rekey(&self) -> anyhow::Result<u64> {
+        if self.double_ratchet.is_some() {
+            bail!("Double Ratchet channels already change keys with every message");
+        }
+
+        let current = self.sync_epochs().await?;
+        let epoch = Arc::clone(&self.epochs.read().await[current]);
+        // Past every entry that could not be opened, as of the sync above.
+        let attempt = epoch.rekey_attempt.load(Ordering::Acquire);
+        let secret = Secret::random();
+        let sealed = epoch.seal_next_secret(current as u64 + 1, attempt, &secret)?;
+        self.message_repository
+            .publish_message(&*epoch.rekey_slot(attempt), &sealed)
+            .await?;
+        // Publishing into a taken slot does not fail, so read it back.
+        let published = self
+            .message_repository
+            .get_message(&*epoch.rekey_slot(attempt))
+            .await?;
+        if published.is_none_or(|p| p.message != sealed) {
+            bail!("Another member rekeyed epoch {current} first");
+        }
+
+        Ok(self.sync_epochs().await? as u64)
+    }
+
+    /// Moves the read position of a member that has run out of messages to
+    /// the first later epoch they have written in. Members only write in the
+    /// newest epoch they know of, so they are done with the current one once
+    /// they have written in a later one.
+    async fn follow_epochs(&self, correspondent_index: u32) -> anyhow::Result<bool> {
+        if self.double_ratchet.is_some() {
+            return Ok(false);
+        }
+
+        let ci = correspondent_index as usize;
+        let newest = self.sync_epochs().await?;
+        let current = self.read_epochs.read().await[ci];
+        let first_index = ci as u64;
+        let nonce = self.nonce_for_message(first_index, correspondent_index)?;
+
+        for number in current + 1..=newest {
+            let epoch = Arc::clone(&self.epochs.read().await[number]);
+            let sequence_hash = epoch.sequence_hash(nonce);
+            if self
+                .message_repository
+                .get_message(&*sequence_hash)
+                .await?
+                .is_some()
+            {
+                self.read_epochs.write().await[ci] = number;
+                self.next_message_read_index.write().await[ci] = first_index;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Returns `None` for our own messages whose keys are gone.
+    #[allow(clippy::too_many_arguments)]
+    async fn decrypt_double_ratchet(
+        &self,
+        state: &Mutex<DoubleRatchet>,
+        epoch: &Epoch,
+        correspondent_index: usize,
+        message_index: u64,
+        nonce: SequenceNumber,
+        sequence_hash: &SequenceHash,
+        message: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some((header, ciphertext)) = Header::split(message) else {
+            bail!("Message too short for a ratchet header");
+        };
+        let mut state = state.lock().await;
+
+        if correspondent_index == self.send_messages_from_member_index {
+            let Some(key) = state.sent_key(message_index) else {
+                return Ok(None);
+            };
+            let cleartext = epoch.decrypt_with_key(&key, nonce, sequence_hash, ciphertext)?;
+            state.forget_sent_key(message_index);
+            return Ok(Some(cleartext));
+        }
+
+        let now_ms = now_ms();
+        let mut next = state.clone();
+        let key = next.receiving_key(&header, now_ms)?;
+        let cleartext = epoch.decrypt_with_key(&key, nonce, sequence_hash, ciphertext)?;
+        next.shred(now_ms);
+        *state = next;
+
+        Ok(Some(cleartext))
     }
 
     pub async fn receive_next_for(
         &self,
         correspondent_index: u32,
     ) -> anyhow::Result<Option<CleartextMessage>> {
-        let message_index = self.next_message_read_index.read().await[correspondent_index as usize];
-        let nonce = self.nonce_for_message(message_index, correspondent_index);
-        let sequence_hash = self.sequence_hash(nonce);
+        let ci = correspondent_index as usize;
+        if let Some(state) = &self.double_ratchet {
+            // Also when no message arrives, so that keys expire on every poll.
+            state.lock().await.shred(now_ms());
+        }
 
-        let response = self.message_repository.get_message(&*sequence_hash).await?;
+        loop {
+            let epoch_number = self.read_epochs.read().await[ci];
+            if epoch_number >= self.epochs.read().await.len() {
+                // Restored from a run that had followed more rekeys already.
+                self.sync_epochs().await?;
+            }
+            let Some(epoch) = self.epochs.read().await.get(epoch_number).cloned() else {
+                bail!("Epoch {epoch_number} has not been published");
+            };
+            let message_index = self.next_message_read_index.read().await[ci];
+            let nonce = self.nonce_for_message(message_index, correspondent_index)?;
+            let sequence_hash = epoch.sequence_hash(nonce);
 
-        let Some(ciphertext) = response else {
-            return Ok(None);
-        };
+            let response = self.message_repository.get_message(&*sequence_hash).await?;
 
-        let cleartext = self.decrypt(nonce, &ciphertext.message)?;
+            let Some(ciphertext) = response else {
+                if self.follow_epochs(correspondent_index).await? {
+                    continue;
+                }
+                return Ok(None);
+            };
 
-        let ci = correspondent_index as usize;
-        let mut next_message_read_index = self.next_message_read_index.write().await;
-        next_message_read_index[ci] += 1;
-        let mut next_message_write_index = self.next_message_write_index.write().await;
-        next_message_write_index[ci] =
-            u32::max(next_message_write_index[ci], next_message_read_index[ci]);
-
-        Ok(Some(CleartextMessage {
-            bytes: cleartext,
-            block_timestamp_ms: ciphertext.block_timestamp_ms,
-        }))
+            let message = match &self.sender_keys {
+                Some(sender_keys) => verify_sender_tag(
+                    &sender_keys[ci],
+                    self.send_messages_from_member_index,
+                    self.members.len(),
+                    &sequence_hash,
+                    &ciphertext.message,
+                ),
+                None => Some(&ciphertext.message[..]),
+            };
+
+            let expired = self
+                .retention()
+                .await
+                .is_some_and(|r| ciphertext.block_timestamp_ms < now_ms().saturating_sub(r));
+
+            let cleartext = match (message, &self.double_ratchet, &epoch.ratchets) {
+                // Written by someone other than the slot's owner.
+                (None, _, _) => None,
+                (Some(message), _, _) if expired => {
+                    self.expire(&epoch, ci, message_index, message).await;
+                    None
+                }
+                (Some(message), Some(state), _) => {
+                    self.decrypt_double_ratchet(
+                        state,
+                        &epoch,
+                        ci,
+                        message_index,
+                        nonce,
+                        &sequence_hash,
+                        message,
+                    )
+                    .await?
+                }
+                (Some(message), None, Some(ratchets)) => {
+                    let mut read = ratchets.read.lock().await;
+                    let (key, next) = read[ci].message_key(message_index)?;
+                    let cleartext = epoch.decrypt_with_key(&key, nonce, &sequence_hash, message)?;
+                    read[ci] = next;
+                    Some(cleartext)
+                }
+                (Some(message), None, None) => {
+                    Some(epoch.decrypt(nonce, &sequence_hash, message)?)
+                }
+            };
+
+            let mut next_message_read_index = self.next_message_read_index.write().await;
+            next_message_read_index[ci] += 1;
+            let mut write_epoch = self.write_epoch.write().await;
+            let mut next_message_write_index = self.next_message_write_index.write().await;
+            if epoch_number == *write_epoch {
+                next_message_write_index[ci] =
+                    u64::max(next_message_write_index[ci], next_message_read_index[ci]);
+            } else if epoch_number > *write_epoch && ci == self.send_messages_from_member_index {
+                // We wrote in this epoch before (e.g. in a previous run).
+                *write_epoch = epoch_number;
+                next_message_write_index[ci] = next_message_read_index[ci];
+            }
+
+            if let Some(state) = &self.double_ratchet {
+                let mut state = state.lock().await;
+                state.read_cursor = Some(next_message_read_index.clone());
+                state.write_cursor = Some(next_message_write_index.clone());
+            }
+            drop((
+                next_message_read_index,
+                write_epoch,
+                next_message_write_index,
+            ));
+            self.save_sender_chains(epoch_number, &epoch).await;
+
+            // Forged and expired messages, and messages we can no longer
+            // decrypt, are skipped.
+            if let Some(cleartext) = cleartext {
+                return Ok(Some(CleartextMessage {
+                    bytes: cleartext,
+                    block_timestamp_ms: ciphertext.block_timestamp_ms,
+                }));
+            }
+        }
     }
 
-    pub fn read_stream(
-        self: &Arc<Self>,
-    ) -> impl ReadStream<Output = (CorrespondentId, CleartextMessage)> {
+    pub fn read_stream(self: &Arc<Self>) -> GroupReadStream {
         MultiplexedReadStream::new(self.members.iter().enumerate().map(|(i, _)| {
             ChunkedReadStream::new(GroupCorrespondentReadStream {
                 group: Arc::clone(self),
@@ -127,29 +857,63 @@ impl Group {
 impl<T: Borrow<Group>> WriteStream for T {
     async fn send<I: ToMessageBytes>(&self, input: I) -> anyhow::Result<()> {
         let s: &Group = self.borrow();
+        let own = s.send_messages_from_member_index;
+
+        let newest = match s.double_ratchet {
+            Some(_) => 0,
+            None => s.sync_epochs().await?,
+        };
 
+        let mut write_epoch = s.write_epoch.write().await;
         let mut next_message_write_index = s.next_message_write_index.write().await;
-        let message_index = next_message_write_index[s.send_messages_from_member_index];
-        next_message_write_index[s.send_messages_from_member_index] += 1;
+        if newest > *write_epoch {
+            *write_epoch = newest;
+            next_message_write_index[own] = own as u64;
+        }
+        let epoch = Arc::clone(&s.epochs.read().await[*write_epoch]);
 
-        let nonce = s.nonce_for_message(message_index, s.send_messages_from_member_index as u32);
-        let sequence_hash = s.sequence_hash(nonce);
-        let ciphertext = s.encrypt(nonce, &input.to_message_bytes())?;
+        let message_index = next_message_write_index[own];
+        let nonce = s.nonce_for_message(message_index, own as u32)?;
+        next_message_write_index[own] += 1;
+
+        let sequence_hash = epoch.sequence_hash(nonce);
+        let message = input.to_message_bytes();
+        let ciphertext = match (&s.double_ratchet, &epoch.ratchets) {
+            (Some(state), _) => {
+                let mut state = state.lock().await;
+                let mut next = state.clone();
+                let (header, key) = next.next_sending_key();
+                let mut ciphertext = header.to_bytes();
+                ciphertext.extend(epoch.encrypt_with_key(&key, nonce, &sequence_hash, &message)?);
+                let now_ms = now_ms();
+                next.remember_sent_key(message_index, key, now_ms);
+                next.shred(now_ms);
+                next.write_cursor = Some(next_message_write_index.clone());
+                *state = next;
+                ciphertext
+            }
+            (None, Some(ratchets)) => {
+                let mut write = ratchets.write.lock().await;
+                let (key, next) = write.message_key(message_index)?;
+                let ciphertext = epoch.encrypt_with_key(&key, nonce, &sequence_hash, &message)?;
+                *write = next;
+                ciphertext
+            }
+            (None, None) => epoch.encrypt(nonce, &sequence_hash, &message)?,
+        };
+        let ciphertext = match &s.sender_keys {
+            Some(sender_keys) => add_sender_tags(sender_keys, &sequence_hash, &ciphertext),
+            None => ciphertext,
+        };
         s.message_repository
             .publish_message(&*sequence_hash, &ciphertext)
             .await?;
 
-        Ok(())
-    }
-}
-
-impl Channel for Group {
-    fn secret_identifier(&self) -> &[u8; 256] {
-        &self.identifier
-    }
+        let epoch_number = *write_epoch;
+        drop((write_epoch, next_message_write_index));
+        s.save_sender_chains(epoch_number, &epoch).await;
 
-    fn shared_secret(&self) -> &[u8; 32] {
-        &self.shared_secret
+        Ok(())
     }
 }
 
@@ -243,3 +1007,81 @@ impl<T: ReadStream<Output = CleartextMessage> + SingleCorrespondentStream + Send
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch(number: u64, secret: [u8; 32]) -> Epoch {
+        let members = vec![[1; 32].into(), [2; 32].into()];
+        let parameters = ChannelParameters {
+            version: ChannelVersion::LATEST,
+            features: vec![],
+        };
+        Epoch::new(&[0; 256], number, &secret.into(), &parameters, &members, 0)
+    }
+
+    #[test]
+    fn next_secret_is_sealed_to_the_current_epoch() {
+        let current = epoch(0, [1; 32]);
+        let next = epoch(1, [2; 32]);
+
+        let sealed = current.seal_next_secret(1, 0, &[2; 32].into()).unwrap();
+        assert_eq!(
+            current.open_next_secret(1, 0, &sealed).unwrap().expose(),
+            &[2; 32]
+        );
+        assert!(current.open_next_secret(2, 0, &sealed).is_err());
+        assert!(next.open_next_secret(1, 0, &sealed).is_err());
+
+        assert_ne!(current.rekey_slot(0), next.rekey_slot(0));
+        assert_ne!(current.sequence_hash(0), next.sequence_hash(0));
+        // Same secret, different epoch.
+        assert_ne!(current.sequence_hash(0), epoch(1, [1; 32]).sequence_hash(0));
+    }
+
+    #[test]
+    fn bogus_rekeys_move_to_the_next_attempt() {
+        let current = epoch(0, [1; 32]);
+
+        // Anyone can write a slot, but only members can seal to it.
+        let bogus = [7; 64];
+        assert!(current.open_next_secret(1, 0, &bogus).is_err());
+        assert!(current.open_next_secret(1, 0, &[7; 3]).is_err());
+
+        assert_ne!(current.rekey_slot(0), current.rekey_slot(1));
+        let sealed = current.seal_next_secret(1, 1, &[2; 32].into()).unwrap();
+        assert_eq!(
+            current.open_next_secret(1, 1, &sealed).unwrap().expose(),
+            &[2; 32]
+        );
+        // A sealed secret cannot be replayed into another attempt's slot.
+        assert!(current.open_next_secret(1, 0, &sealed).is_err());
+        assert!(current.open_next_secret(1, 2, &sealed).is_err());
+    }
+
+    #[test]
+    fn only_the_slot_owner_can_tag() {
+        let secrets = [[1; 32], [2; 32], [3; 32]].map(StaticSecret::from);
+        let members = secrets
+            .iter()
+            .map(|s| CorrespondentId::from(PublicKey::from(s).to_bytes()))
+            .collect::<Vec<_>>();
+        let keys_of =
+            |s: &StaticSecret| members.iter().map(|m| sender_key(s, m)).collect::<Vec<_>>();
+        let sequence_hash = SequenceHash::from([9; 32]);
+
+        // Member 0 writes, member 1 checks the tag addressed to it.
+        let tagged = add_sender_tags(&keys_of(&secrets[0]), &sequence_hash, b"ciphertext");
+        let bob_keys = keys_of(&secrets[1]);
+        assert_eq!(
+            verify_sender_tag(&bob_keys[0], 1, 3, &sequence_hash, &tagged),
+            Some(&b"ciphertext"[..]),
+        );
+        assert!(verify_sender_tag(&bob_keys[0], 1, 3, &[8; 32].into(), &tagged).is_none());
+
+        // Member 2 knows the channel key, but cannot write as member 0.
+        let forged = add_sender_tags(&keys_of(&secrets[2]), &sequence_hash, b"ciphertext");
+        assert!(verify_sender_tag(&bob_keys[0], 1, 3, &sequence_hash, &forged).is_none());
+    }
+}
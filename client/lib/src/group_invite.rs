@@ -0,0 +1,708 @@
+use anyhow::bail;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+
+use crate::{
+    channel::{ChannelFeature, ChannelParameters, ChannelVersion, CorrespondentId, SequenceHash},
+    secret::Secret,
+};
+
+const INVITE_FORMAT: u8 = 0;
+const MAX_MEMBERS: usize = u8::MAX as usize;
+
+/// Group channels are set up by an invite rather than negotiated between two
+/// key records, so the features are fixed by the invite format and the
+/// version is carried in the invite.
+pub fn parameters(version: ChannelVersion) -> ChannelParameters {
+    ChannelParameters {
+        version,
+        features: vec![
+            ChannelFeature::Ratchet,
+            ChannelFeature::SenderAuthentication,
+            ChannelFeature::KeyCommitment,
+        ],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    Admin,
+}
+
+impl Role {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Member => 0,
+            Self::Admin => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Member),
+            1 => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Who may change a group. Admins are known by the key they sign their
+/// changes with, so a member can only be made an admin by someone who has
+/// that key, e.g. from [`crate::messenger::Messenger::signing_key`].
+/// Changing the admins or the policy itself always takes an admin, and so
+/// does removing an admin from the group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupPolicy {
+    /// The role needed to add or remove members.
+    pub membership: Role,
+    /// The role needed to rename the group.
+    pub metadata: Role,
+    admins: Vec<(PublicKey, VerifyingKey)>,
+}
+
+/// Any member may change the group, as in groups without admins.
+impl Default for GroupPolicy {
+    fn default() -> Self {
+        Self::new(Role::Member, Role::Member)
+    }
+}
+
+impl GroupPolicy {
+    pub fn new(membership: Role, metadata: Role) -> Self {
+        Self {
+            membership,
+            metadata,
+            admins: vec![],
+        }
+    }
+
+    pub fn with_admin(mut self, member: &PublicKey, signing_key: &VerifyingKey) -> Self {
+        self.admins.retain(|(m, _)| m != member);
+        self.admins.push((*member, *signing_key));
+        self.admins.sort_by_key(|(m, _)| m.to_bytes());
+        self
+    }
+
+    pub fn without_admin(mut self, member: &PublicKey) -> Self {
+        self.admins.retain(|(m, _)| m != member);
+        self
+    }
+
+    pub fn role(&self, member: &PublicKey) -> Role {
+        if self.admin_key(member).is_some() {
+            Role::Admin
+        } else {
+            Role::Member
+        }
+    }
+
+    pub fn admin_key(&self, member: &PublicKey) -> Option<&VerifyingKey> {
+        self.admins
+            .iter()
+            .find_map(|(m, signing_key)| (m == member).then_some(signing_key))
+    }
+
+    pub fn admins(&self) -> impl Iterator<Item = &PublicKey> {
+        self.admins.iter().map(|(m, _)| m)
+    }
+
+    /// Admins who are no longer members lose their role with their
+    /// membership.
+    fn retain_members(mut self, members: &[PublicKey]) -> Self {
+        self.admins.retain(|(m, _)| members.contains(m));
+        self
+    }
+}
+
+/// Everything needed to open one generation of a group channel, signed by
+/// the member who created it. Every change of membership starts a new
+/// generation with a new secret. Sent to each member through their direct
+/// channel with the creator, so it contains the group secret and must not be
+/// sent anywhere else.
+#[derive(Debug, Clone)]
+pub struct GroupInvite {
+    /// The member who created this generation.
+    pub creator: PublicKey,
+    pub signing_key: VerifyingKey,
+    /// Distinguishes groups that have the same members. The same for every
+    /// generation of a group.
+    pub group_id: [u8; 32],
+    pub generation: u64,
+    /// Chosen when the group is created and kept by every generation, so
+    /// that members with newer clients derive the same channels.
+    pub version: ChannelVersion,
+    pub secret: Secret,
+    /// Every member, including the creator.
+    pub members: Vec<PublicKey>,
+    pub policy: GroupPolicy,
+    pub name: String,
+    pub signature: Signature,
+}
+
+/// Sorted, so that every member derives the same channel.
+fn sorted_members(
+    creator: &PublicKey,
+    mut members: Vec<PublicKey>,
+) -> anyhow::Result<Vec<PublicKey>> {
+    if !members.contains(creator) {
+        members.push(*creator);
+    }
+    members.sort_by_key(|m| m.to_bytes());
+    members.dedup();
+    if members.len() > MAX_MEMBERS {
+        bail!("Groups can have at most {MAX_MEMBERS} members");
+    }
+    Ok(members)
+}
+
+impl GroupInvite {
+    /// Everything but the signature, which is over this with a prefix.
+    fn body_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            1 + 4
+                + 32 * 4
+                + 8
+                + 1
+                + self.members.len() * 32
+                + 3
+                + self.policy.admins.len() * 64
+                + self.name.len(),
+        );
+        buf.push(INVITE_FORMAT);
+        buf.extend(self.version.to_u32().to_le_bytes());
+        buf.extend(self.creator.as_bytes());
+        buf.extend(self.signing_key.as_bytes());
+        buf.extend(self.group_id);
+        buf.extend(self.generation.to_le_bytes());
+        buf.extend(self.secret.expose());
+        buf.push(self.members.len() as u8);
+        for member in &self.members {
+            buf.extend(member.as_bytes());
+        }
+        buf.push(self.policy.membership.to_byte());
+        buf.push(self.policy.metadata.to_byte());
+        buf.push(self.policy.admins.len() as u8);
+        for (member, signing_key) in &self.policy.admins {
+            buf.extend(member.as_bytes());
+            buf.extend(signing_key.as_bytes());
+        }
+        buf.extend(self.name.as_bytes());
+        buf
+    }
+
+    fn signed_bytes(body: &[u8]) -> Vec<u8> {
+        [b"fchan group invite".as_slice(), body].concat()
+    }
+
+    fn sign(mut self, signing_key: &SigningKey) -> Self {
+        self.signing_key = signing_key.verifying_key();
+        self.signature = signing_key.sign(&Self::signed_bytes(&self.body_bytes()));
+        self
+    }
+
+    /// A new group of `members` with a fresh secret, which any member may
+    /// change. The creator is added to `members` if it is not in it already.
+    pub fn new(
+        creator: &PublicKey,
+        signing_key: &SigningKey,
+        members: Vec<PublicKey>,
+        name: &str,
+    ) -> anyhow::Result<Self> {
+        Self::new_with_policy(creator, signing_key, members, name, GroupPolicy::default())
+    }
+
+    /// Like [`GroupInvite::new`], with `policy` and the creator as an admin.
+    /// Under the default policy nobody is made an admin, so that any member
+    /// may remove the creator and the creator may leave.
+    pub fn new_with_policy(
+        creator: &PublicKey,
+        signing_key: &SigningKey,
+        members: Vec<PublicKey>,
+        name: &str,
+        policy: GroupPolicy,
+    ) -> anyhow::Result<Self> {
+        let mut group_id = [0u8; 32];
+        OsRng.fill_bytes(&mut group_id);
+        let members = sorted_members(creator, members)?;
+        let policy = if policy == GroupPolicy::default() {
+            policy
+        } else {
+            policy.with_admin(creator, &signing_key.verifying_key())
+        };
+
+        Ok(Self {
+            creator: *creator,
+            signing_key: signing_key.verifying_key(),
+            group_id,
+            generation: 0,
+            version: ChannelVersion::LATEST,
+            secret: Secret::random(),
+            policy: policy.retain_members(&members),
+            members,
+            name: name.to_string(),
+            signature: Signature::from_bytes(&[0; 64]),
+        }
+        .sign(signing_key))
+    }
+
+    /// The next generation of this group, with `members` and a fresh secret,
+    /// created by `creator`.
+    pub fn next(
+        &self,
+        creator: &PublicKey,
+        signing_key: &SigningKey,
+        members: Vec<PublicKey>,
+    ) -> anyhow::Result<Self> {
+        self.next_with(
+            creator,
+            signing_key,
+            members,
+            &self.name,
+            self.policy.clone(),
+        )
+    }
+
+    /// Like [`GroupInvite::next`], also changing the name and the policy.
+    pub fn next_with(
+        &self,
+        creator: &PublicKey,
+        signing_key: &SigningKey,
+        members: Vec<PublicKey>,
+        name: &str,
+        policy: GroupPolicy,
+    ) -> anyhow::Result<Self> {
+        let Some(generation) = self.generation.checked_add(1) else {
+            bail!("Group has run out of generations");
+        };
+        let members = sorted_members(creator, members)?;
+
+        Ok(Self {
+            creator: *creator,
+            signing_key: signing_key.verifying_key(),
+            group_id: self.group_id,
+            generation,
+            version: self.version,
+            secret: Secret::random(),
+            policy: policy.retain_members(&members),
+            members,
+            name: name.to_string(),
+            signature: Signature::from_bytes(&[0; 64]),
+        }
+        .sign(signing_key))
+    }
+
+    /// The role that `next` needs its creator to have in this generation.
+    fn required_role(&self, next: &GroupInvite) -> Role {
+        let mut required = Role::Member;
+        if next.members != self.members {
+            required = required.max(self.policy.membership);
+        }
+        if next.name != self.name {
+            required = required.max(self.policy.metadata);
+        }
+        // Checked before comparing the policies, which lose removed admins
+        // along with their membership.
+        if self
+            .policy
+            .admins()
+            .any(|admin| next.policy.admin_key(admin).is_none())
+        {
+            required = Role::Admin;
+        }
+        if next.policy != self.policy.clone().retain_members(&next.members) {
+            required = Role::Admin;
+        }
+        required
+    }
+
+    /// Checks that `next` is a valid successor of this generation, i.e. that
+    /// it was created by one of our members.
+    pub fn verify_next(&self, next: &GroupInvite) -> anyhow::Result<()> {
+        next.verify()?;
+        if next.group_id != self.group_id {
+            bail!("Group invite is for another group");
+        }
+        if Some(next.generation) != self.generation.checked_add(1) {
+            bail!(
+                "Expected generation {} of the group, got {}",
+                self.generation + 1,
+                next.generation,
+            );
+        }
+        if next.version != self.version {
+            bail!("Group invite changes the channel version");
+        }
+        if !self.members.contains(&next.creator) {
+            bail!("Group changed by someone who is not a member");
+        }
+        if self.required_role(next) == Role::Admin
+            && self.policy.admin_key(&next.creator) != Some(&next.signing_key)
+        {
+            bail!("Only an admin may make this change to the group");
+        }
+        if self.policy.admins().next().is_some() && next.policy.admins().next().is_none() {
+            bail!("Group change leaves the group without admins");
+        }
+        Ok(())
+    }
+
+    /// Channels of different generations never share slots.
+    pub fn context(&self) -> Vec<u8> {
+        [self.group_id.as_slice(), &self.generation.to_le_bytes()].concat()
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        <Sha256 as Digest>::new()
+            .chain_update(b"fchan group invite hash")
+            .chain_update(self.to_bytes())
+            .finalize()
+            .into()
+    }
+
+    fn change_mac(&self, label: &[u8]) -> Hmac<Sha256> {
+        // unwrap ok because HMAC accepts keys of any length
+        <Hmac<Sha256> as Mac>::new_from_slice(self.secret.expose())
+            .unwrap()
+            .chain_update(label)
+            .chain_update(self.context())
+    }
+
+    /// Where the hash of the generation after this one is published. Slots
+    /// can only be written once, so concurrent changes resolve to the same
+    /// generation for every member. Only members know the slots, and an
+    /// entry that cannot be opened moves the change to the next attempt.
+    pub fn change_slot(&self, attempt: u32) -> SequenceHash {
+        let hash_bytes: [u8; 32] = self
+            .change_mac(b"fchan group change slot")
+            .chain_update(attempt.to_le_bytes())
+            .finalize()
+            .into_bytes()
+            .into();
+        hash_bytes.into()
+    }
+
+    fn change_cipher(&self) -> XChaCha20Poly1305 {
+        let key = self
+            .change_mac(b"fchan group change")
+            .finalize()
+            .into_bytes();
+        XChaCha20Poly1305::new(&key)
+    }
+
+    /// Encrypts the hash of `next` to this generation, for attempt `attempt`.
+    pub fn seal_change(&self, next: &GroupInvite, attempt: u32) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = XNonce::default();
+        OsRng.fill_bytes(&mut nonce);
+        let slot = self.change_slot(attempt);
+        let ciphertext = match self.change_cipher().encrypt(
+            &nonce,
+            Payload {
+                msg: &next.hash(),
+                aad: slot.as_ref(),
+            },
+        ) {
+            Ok(c) => c,
+            Err(e) => bail!(e),
+        };
+
+        let mut buf = nonce.to_vec();
+        buf.extend(ciphertext);
+        Ok(buf)
+    }
+
+    /// The hash of the next generation in an entry of attempt `attempt`, if
+    /// it was sealed by a member.
+    pub fn open_change(&self, sealed: &[u8], attempt: u32) -> Option<[u8; 32]> {
+        let (nonce, ciphertext) = sealed.split_at_checked(24)?;
+        let slot = self.change_slot(attempt);
+        let hash = self
+            .change_cipher()
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: slot.as_ref(),
+                },
+            )
+            .ok()?;
+        hash.try_into().ok()
+    }
+
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let bytes = Self::signed_bytes(&self.body_bytes());
+        if let Err(e) = self.signing_key.verify(&bytes, &self.signature) {
+            bail!("Invalid group invite signature: {e}");
+        }
+        if self.policy.admins().any(|a| !self.members.contains(a)) {
+            bail!("Group admins must be members");
+        }
+        Ok(())
+    }
+
+    pub fn member_ids(&self) -> Vec<CorrespondentId> {
+        self.members.iter().map(|m| m.to_bytes().into()).collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.body_bytes();
+        buf.extend(self.signature.to_bytes());
+        buf
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (body, signature) = bytes.split_at_checked(bytes.len().checked_sub(64)?)?;
+        if body.len() < 1 + 4 + 32 * 4 + 8 + 1 + 3 || body[0] != INVITE_FORMAT {
+            return None;
+        }
+        let version = ChannelVersion::from_u32(u32::from_le_bytes(body[1..5].try_into().ok()?))?;
+        let body = &body[4..];
+        let creator: [u8; 32] = body[1..33].try_into().ok()?;
+        let signing_key = VerifyingKey::from_bytes(body[33..65].try_into().ok()?).ok()?;
+        let group_id: [u8; 32] = body[65..97].try_into().ok()?;
+        let generation = u64::from_le_bytes(body[97..105].try_into().ok()?);
+        let secret: [u8; 32] = body[105..137].try_into().ok()?;
+        let member_count = body[137] as usize;
+        let (members, rest) = body[138..].split_at_checked(member_count * 32)?;
+        let members = members
+            .chunks_exact(32)
+            // unwrap ok because chunks are exactly 32 bytes
+            .map(|m| PublicKey::from(<[u8; 32]>::try_from(m).unwrap()))
+            .collect();
+        let membership = Role::from_byte(*rest.first()?)?;
+        let metadata = Role::from_byte(*rest.get(1)?)?;
+        let admin_count = *rest.get(2)? as usize;
+        let (admins, name) = rest[3..].split_at_checked(admin_count * 64)?;
+        let admins = admins
+            .chunks_exact(64)
+            .map(|a| {
+                let member: [u8; 32] = a[..32].try_into().ok()?;
+                let signing_key = VerifyingKey::from_bytes(a[32..].try_into().ok()?).ok()?;
+                Some((PublicKey::from(member), signing_key))
+            })
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            creator: creator.into(),
+            signing_key,
+            group_id,
+            generation,
+            version,
+            secret: Secret::from(secret),
+            members,
+            policy: GroupPolicy {
+                membership,
+                metadata,
+                admins,
+            },
+            name: String::from_utf8(name.to_vec()).ok()?,
+            signature: Signature::from_bytes(signature.try_into().ok()?),
+        })
+    }
+}
+
+/// Only the public parts are compared, since the signature covers the secret.
+impl PartialEq for GroupInvite {
+    fn eq(&self, other: &Self) -> bool {
+        self.creator == other.creator
+            && self.signing_key == other.signing_key
+            && self.group_id == other.group_id
+            && self.generation == other.generation
+            && self.version == other.version
+            && self.members == other.members
+            && self.policy == other.policy
+            && self.name == other.name
+            && self.signature == other.signature
+    }
+}
+
+impl Eq for GroupInvite {}
+
+#[cfg(test)]
+mod tests {
+    use x25519_dalek::StaticSecret;
+
+    use super::*;
+
+    #[test]
+    fn invite_roundtrip_and_signature() {
+        let creator = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let member = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+
+        let invite = GroupInvite::new(&creator, &signing_key, vec![member], "team").unwrap();
+        assert_eq!(invite.members.len(), 2);
+        invite.verify().unwrap();
+
+        let decoded = GroupInvite::try_from_bytes(&invite.to_bytes()).unwrap();
+        assert_eq!(decoded, invite);
+        assert_eq!(decoded.secret.expose(), invite.secret.expose());
+        decoded.verify().unwrap();
+
+        let mut renamed = decoded.clone();
+        renamed.name = "other".to_string();
+        assert!(renamed.verify().is_err());
+
+        // The version is carried and signed, and kept by later generations.
+        assert_eq!(decoded.version, ChannelVersion::LATEST);
+        let mut downgraded = decoded.clone();
+        downgraded.version = ChannelVersion::V3;
+        assert!(downgraded.verify().is_err());
+        let next = invite.next(&member, &signing_key, vec![]).unwrap();
+        assert_eq!(next.version, invite.version);
+        invite.verify_next(&next).unwrap();
+        let mut forked = next.clone();
+        forked.version = ChannelVersion::V3;
+        assert!(invite.verify_next(&forked.sign(&signing_key)).is_err());
+    }
+
+    #[test]
+    fn changes_are_sealed_to_their_generation_and_attempt() {
+        let creator = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let invite = GroupInvite::new(&creator, &signing_key, vec![], "team").unwrap();
+        let next = invite.next(&creator, &signing_key, vec![]).unwrap();
+
+        let sealed = invite.seal_change(&next, 0).unwrap();
+        assert_eq!(invite.open_change(&sealed, 0), Some(next.hash()));
+        assert_eq!(invite.open_change(&sealed, 1), None);
+        assert_eq!(next.open_change(&sealed, 0), None);
+        assert_eq!(invite.open_change(&[7; 64], 0), None);
+
+        assert_ne!(invite.change_slot(0), invite.change_slot(1));
+        assert_ne!(invite.change_slot(0), next.change_slot(0));
+    }
+
+    #[test]
+    fn only_members_start_the_next_generation() {
+        let creator = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let member = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let outsider = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+
+        let invite = GroupInvite::new(&creator, &signing_key, vec![member], "team").unwrap();
+        let next = invite.next(&member, &signing_key, vec![]).unwrap();
+        assert_eq!(next.generation, 1);
+        assert_eq!(next.members, vec![member]);
+        assert_ne!(next.context(), invite.context());
+        invite.verify_next(&next).unwrap();
+        assert!(next.verify_next(&invite).is_err());
+
+        let forged = invite.next(&outsider, &signing_key, vec![member]).unwrap();
+        assert!(invite.verify_next(&forged).is_err());
+        let skipped = next.next(&member, &signing_key, vec![creator]).unwrap();
+        assert!(invite.verify_next(&skipped).is_err());
+    }
+
+    #[test]
+    fn admin_changes_need_the_admin_key() {
+        let admin = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let member = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let newcomer = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let admin_key = SigningKey::from_bytes(&[7; 32]);
+        let member_key = SigningKey::from_bytes(&[8; 32]);
+
+        let policy = GroupPolicy::new(Role::Admin, Role::Member);
+        let invite =
+            GroupInvite::new_with_policy(&admin, &admin_key, vec![member], "team", policy).unwrap();
+        assert_eq!(invite.policy.role(&admin), Role::Admin);
+        assert_eq!(invite.policy.role(&member), Role::Member);
+        let decoded = GroupInvite::try_from_bytes(&invite.to_bytes()).unwrap();
+        assert_eq!(decoded, invite);
+
+        let renamed = invite
+            .next_with(
+                &member,
+                &member_key,
+                invite.members.clone(),
+                "crew",
+                invite.policy.clone(),
+            )
+            .unwrap();
+        invite.verify_next(&renamed).unwrap();
+
+        let added = invite
+            .next(&member, &member_key, vec![admin, member, newcomer])
+            .unwrap();
+        assert!(invite.verify_next(&added).is_err());
+        // Claiming to be the admin does not help without the admin's key.
+        let forged = invite
+            .next(&admin, &member_key, vec![admin, member, newcomer])
+            .unwrap();
+        assert!(invite.verify_next(&forged).is_err());
+        let added = invite
+            .next(&admin, &admin_key, vec![admin, member, newcomer])
+            .unwrap();
+        invite.verify_next(&added).unwrap();
+
+        let promoted = invite
+            .policy
+            .clone()
+            .with_admin(&member, &member_key.verifying_key());
+        let coup = invite
+            .next_with(
+                &member,
+                &member_key,
+                invite.members.clone(),
+                "team",
+                promoted.clone(),
+            )
+            .unwrap();
+        assert!(invite.verify_next(&coup).is_err());
+        let promotion = invite
+            .next_with(&admin, &admin_key, invite.members.clone(), "team", promoted)
+            .unwrap();
+        invite.verify_next(&promotion).unwrap();
+        assert_eq!(promotion.policy.role(&member), Role::Admin);
+    }
+
+    #[test]
+    fn removing_admins_needs_an_admin() {
+        let admin = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let member = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let other = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let admin_key = SigningKey::from_bytes(&[7; 32]);
+        let member_key = SigningKey::from_bytes(&[8; 32]);
+
+        // Without admins, any member may remove the creator.
+        let invite = GroupInvite::new(&admin, &admin_key, vec![member, other], "team").unwrap();
+        assert_eq!(invite.policy.admins().count(), 0);
+        let removed = invite
+            .next(&member, &member_key, vec![member, other])
+            .unwrap();
+        invite.verify_next(&removed).unwrap();
+
+        // Any member may change the membership, but not drop an admin.
+        let policy = GroupPolicy::default().with_admin(&admin, &admin_key.verifying_key());
+        let invite =
+            GroupInvite::new_with_policy(&admin, &admin_key, vec![member, other], "team", policy)
+                .unwrap();
+        let removed = invite
+            .next(&member, &member_key, vec![member, other])
+            .unwrap();
+        assert!(invite.verify_next(&removed).is_err());
+        let removed = invite
+            .next(&member, &member_key, vec![admin, member])
+            .unwrap();
+        invite.verify_next(&removed).unwrap();
+
+        // Nor may the last admin leave the group without one.
+        let abdication = invite
+            .next_with(
+                &admin,
+                &admin_key,
+                invite.members.clone(),
+                "team",
+                invite.policy.clone().without_admin(&admin),
+            )
+            .unwrap();
+        assert!(invite.verify_next(&abdication).is_err());
+    }
+}
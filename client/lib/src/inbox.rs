@@ -0,0 +1,272 @@
+use anyhow::bail;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use near_primitives::types::AccountId;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::channel::SequenceHash;
+
+const SEALED_SENDER_LENGTH: usize = 32 + 16;
+const WORK_NONCE_LENGTH: usize = 8;
+
+/// Leading zero bits that the work hash of an entry needs, so that every
+/// entry costs its sender work however many accounts they hold.
+pub const WORK_BITS: u32 = 16;
+
+/// Slot `index` of the inbox. There is one inbox for everyone, so that an
+/// entry does not tell observers whom it is for. Recipients try to open
+/// every entry.
+pub fn inbox_slot(index: u64) -> SequenceHash {
+    let hash_bytes: [u8; 32] = <Sha256 as Digest>::new()
+        .chain_update(b"fchan inbox")
+        .chain_update(index.to_le_bytes())
+        .finalize()
+        .into();
+    hash_bytes.into()
+}
+
+/// Announces a sender who wants to talk. Only the recipient can read it, and
+/// it can only have been sealed by the holder of `sender_key`. The account
+/// ID is a claim, to be checked against the key registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirstContact {
+    pub sender_key: PublicKey,
+    pub account_id: AccountId,
+    pub message: String,
+}
+
+fn diffie_hellman(secret: &StaticSecret, public_key: &PublicKey) -> anyhow::Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(public_key);
+    if !shared.was_contributory() {
+        bail!("Non-contributory key agreement");
+    }
+    Ok(shared.to_bytes())
+}
+
+/// Covers the entry alone. Binding it to the recipient would let anyone
+/// with a registered key pick that recipient's entries out of the inbox.
+fn work_hasher() -> Sha256 {
+    <Sha256 as Digest>::new().chain_update(b"fchan inbox work")
+}
+
+fn has_work(hasher: Sha256, nonce: &[u8]) -> bool {
+    let hash = hasher.chain_update(nonce).finalize();
+    // unwrap ok because a SHA-256 hash is 32 bytes
+    let zeros = u128::from_be_bytes(hash[..16].try_into().unwrap()).leading_zeros();
+    zeros >= WORK_BITS
+}
+
+/// Every entry has its own ephemeral key, so a fixed nonce is never reused.
+fn inbox_cipher(
+    label: &[u8],
+    agreements: &[[u8; 32]],
+    ephemeral_key: &PublicKey,
+    recipient: &PublicKey,
+) -> XChaCha20Poly1305 {
+    let mut ikm = Zeroizing::new(Vec::with_capacity(agreements.len() * 32));
+    for agreement in agreements {
+        ikm.extend(agreement);
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(label), &ikm)
+        .expand_multi_info(&[ephemeral_key.as_bytes(), recipient.as_bytes()], &mut *key)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    XChaCha20Poly1305::new((&*key).into())
+}
+
+/// The sender's key is encrypted to the recipient alone, and the rest also
+/// to the sender's key, which authenticates the sender to the recipient
+/// without revealing them to anyone else. Ends with a nonce for which the
+/// entry has [`WORK_BITS`] of work.
+pub fn seal(
+    sender_secret: &StaticSecret,
+    recipient: &PublicKey,
+    account_id: &AccountId,
+    message: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret);
+    let to_recipient = Zeroizing::new(diffie_hellman(&ephemeral_secret, recipient)?);
+    let from_sender = Zeroizing::new(diffie_hellman(sender_secret, recipient)?);
+
+    let mut buf = ephemeral_key.as_bytes().to_vec();
+    let sender_key = inbox_cipher(
+        b"fchan inbox sender",
+        &[*to_recipient],
+        &ephemeral_key,
+        recipient,
+    )
+    .encrypt(
+        &XNonce::default(),
+        PublicKey::from(sender_secret).as_bytes().as_slice(),
+    );
+    match sender_key {
+        Ok(c) => buf.extend(c),
+        Err(e) => bail!(e),
+    }
+
+    // Account IDs are at most 64 bytes long.
+    let account_id = account_id.as_str();
+    let mut body = Vec::with_capacity(1 + account_id.len() + message.len());
+    body.push(account_id.len() as u8);
+    body.extend(account_id.as_bytes());
+    body.extend(message.as_bytes());
+    let body = inbox_cipher(
+        b"fchan inbox body",
+        &[*to_recipient, *from_sender],
+        &ephemeral_key,
+        recipient,
+    )
+    .encrypt(
+        &XNonce::default(),
+        Payload {
+            msg: &body,
+            aad: &buf,
+        },
+    );
+    match body {
+        Ok(c) => buf.extend(c),
+        Err(e) => bail!(e),
+    }
+
+    let hasher = work_hasher().chain_update(&buf);
+    // unwrap ok because a nonce is found long before the search runs out
+    let nonce = (0..u64::MAX)
+        .map(u64::to_le_bytes)
+        .find(|nonce| has_work(hasher.clone(), nonce))
+        .unwrap();
+    buf.extend(nonce);
+
+    Ok(buf)
+}
+
+/// Checks the work of an entry, which is cheap enough to run on every entry
+/// in the inbox. Whom the entry is for only shows when opening it.
+pub fn has_enough_work(sealed: &[u8]) -> bool {
+    let Some(split) = sealed.len().checked_sub(WORK_NONCE_LENGTH) else {
+        return false;
+    };
+    let (sealed, nonce) = sealed.split_at(split);
+    has_work(work_hasher().chain_update(sealed), nonce)
+}
+
+pub fn open(recipient_secret: &StaticSecret, sealed: &[u8]) -> anyhow::Result<FirstContact> {
+    if sealed.len() < 32 + SEALED_SENDER_LENGTH + WORK_NONCE_LENGTH {
+        bail!("Inbox entry too short");
+    }
+    let recipient = PublicKey::from(recipient_secret);
+    if !has_enough_work(sealed) {
+        bail!("Inbox entry lacks proof of work");
+    }
+    let sealed = &sealed[..sealed.len() - WORK_NONCE_LENGTH];
+    // unwrap ok because the length was checked above
+    let ephemeral_key = PublicKey::from(<[u8; 32]>::try_from(&sealed[..32]).unwrap());
+    let (header, body) = sealed.split_at(32 + SEALED_SENDER_LENGTH);
+    let to_recipient = Zeroizing::new(diffie_hellman(recipient_secret, &ephemeral_key)?);
+
+    let sender_key = match inbox_cipher(
+        b"fchan inbox sender",
+        &[*to_recipient],
+        &ephemeral_key,
+        &recipient,
+    )
+    .decrypt(&XNonce::default(), &header[32..])
+    {
+        Ok(k) => k,
+        Err(e) => bail!(e),
+    };
+    // unwrap ok because the ciphertext length was fixed
+    let sender_key = PublicKey::from(<[u8; 32]>::try_from(sender_key.as_slice()).unwrap());
+    let from_sender = Zeroizing::new(diffie_hellman(recipient_secret, &sender_key)?);
+
+    let body = match inbox_cipher(
+        b"fchan inbox body",
+        &[*to_recipient, *from_sender],
+        &ephemeral_key,
+        &recipient,
+    )
+    .decrypt(
+        &XNonce::default(),
+        Payload {
+            msg: body,
+            aad: header,
+        },
+    ) {
+        Ok(b) => b,
+        Err(e) => bail!(e),
+    };
+
+    let Some((&account_id_length, rest)) = body.split_first() else {
+        bail!("Empty inbox entry");
+    };
+    let Some((account_id, message)) = rest.split_at_checked(account_id_length as usize) else {
+        bail!("Inbox entry too short for its account ID");
+    };
+    let Ok(account_id) = std::str::from_utf8(account_id)?.parse() else {
+        bail!("Invalid account ID in inbox entry");
+    };
+
+    Ok(FirstContact {
+        sender_key,
+        account_id,
+        message: String::from_utf8(message.to_vec())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_recipient_can_open() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let eve = StaticSecret::random_from_rng(OsRng);
+        let account_id: AccountId = "alice.near".parse().unwrap();
+
+        let sealed = seal(&alice, &PublicKey::from(&bob), &account_id, "hi bob").unwrap();
+        assert_eq!(
+            open(&bob, &sealed).unwrap(),
+            FirstContact {
+                sender_key: PublicKey::from(&alice),
+                account_id,
+                message: "hi bob".to_string(),
+            },
+        );
+        assert!(open(&eve, &sealed).is_err());
+
+        // The sealed sender key cannot be tampered with.
+        let mut forged = sealed.clone();
+        forged[40] ^= 1;
+        assert!(open(&bob, &forged).is_err());
+    }
+
+    #[test]
+    fn entries_need_work() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let account_id: AccountId = "alice.near".parse().unwrap();
+
+        let sealed = seal(&alice, &PublicKey::from(&bob), &account_id, "hi bob").unwrap();
+        assert!(has_enough_work(&sealed));
+        assert!(open(&bob, &sealed).is_ok());
+
+        let mut lazy = sealed.clone();
+        let nonce_start = lazy.len() - WORK_NONCE_LENGTH;
+        // Almost every nonce lacks the work, so the first few will do.
+        (0..u64::MAX)
+            .map(u64::to_le_bytes)
+            .find(|nonce| {
+                lazy[nonce_start..].copy_from_slice(nonce);
+                !has_enough_work(&lazy)
+            })
+            .unwrap();
+        assert!(open(&bob, &lazy).is_err());
+    }
+}
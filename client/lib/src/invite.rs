@@ -0,0 +1,217 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+use argon2::Argon2;
+use data_encoding::BASE64URL_NOPAD;
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    channel::{ChannelFeature, ChannelParameters, ChannelVersion, SequenceHash},
+    secret::Secret,
+};
+
+const TOKEN_FORMAT: u8 = 0;
+const URI_PREFIX: &str = "fchan:invite/";
+
+/// Invite channels are opened without a key record to negotiate with, so
+/// the parameters are fixed by the token format. A newer version needs a new
+/// format, or clients of different ages would derive different channels.
+pub fn parameters() -> ChannelParameters {
+    ChannelParameters {
+        version: ChannelVersion::V4,
+        features: vec![ChannelFeature::KeyCommitment],
+    }
+}
+
+/// Shared out of band to let someone open a channel with us, without either
+/// party being in the key registry. Anyone who sees the token can answer it,
+/// and only the first answer counts, so it should be shared privately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteToken {
+    pub public_key: PublicKey,
+    pub context: [u8; 32],
+}
+
+impl InviteToken {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + 32 + 32);
+        buf.push(TOKEN_FORMAT);
+        buf.extend(self.public_key.as_bytes());
+        buf.extend(self.context);
+        buf
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 1 + 32 + 32 || bytes[0] != TOKEN_FORMAT {
+            return None;
+        }
+        let public_key: [u8; 32] = bytes[1..33].try_into().ok()?;
+        let context: [u8; 32] = bytes[33..65].try_into().ok()?;
+
+        Some(Self {
+            public_key: public_key.into(),
+            context,
+        })
+    }
+
+    /// Where the answering party publishes their public key. Derived from
+    /// the context, so it cannot be found from chain data alone.
+    pub fn answer_slot(&self) -> SequenceHash {
+        let hash_bytes: [u8; 32] = <Sha256 as Digest>::new()
+            .chain_update(b"fchan invite answer")
+            .chain_update(self.public_key.as_bytes())
+            .chain_update(self.context)
+            .finalize()
+            .into();
+        hash_bytes.into()
+    }
+}
+
+impl fmt::Display for InviteToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{URI_PREFIX}{}",
+            BASE64URL_NOPAD.encode(&self.to_bytes())
+        )
+    }
+}
+
+impl FromStr for InviteToken {
+    type Err = anyhow::Error;
+
+    /// Accepts the token with or without the URI prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.trim();
+        let encoded = encoded.strip_prefix(URI_PREFIX).unwrap_or(encoded);
+        let Ok(bytes) = BASE64URL_NOPAD.decode(encoded.as_bytes()) else {
+            bail!("Invite token is not valid base64");
+        };
+        match Self::try_from_bytes(&bytes) {
+            Some(token) => Ok(token),
+            None => bail!("Invalid invite token"),
+        }
+    }
+}
+
+/// The creator's side of an invite. The secret is needed to open the
+/// channel once the invite has been answered, so store it accordingly.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invite {
+    secret_key: Secret,
+    context: [u8; 32],
+}
+
+impl Invite {
+    pub fn new() -> Self {
+        let mut context = [0u8; 32];
+        OsRng.fill_bytes(&mut context);
+        Self {
+            secret_key: Secret::random(),
+            context,
+        }
+    }
+
+    /// Derives the invite from a passphrase, so that everyone who knows it
+    /// arrives at the same token. The passphrase is stretched with Argon2id,
+    /// but a guessable passphrase still exposes the channel to anyone who
+    /// reads the answer from the chain.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> anyhow::Result<Self> {
+        let mut okm = Zeroizing::new([0u8; 64]);
+        let stretched =
+            Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut *okm);
+        if let Err(e) = stretched {
+            bail!("Failed to stretch passphrase: {e}");
+        }
+
+        let mut secret_key = [0u8; 32];
+        secret_key.copy_from_slice(&okm[..32]);
+        let mut context = [0u8; 32];
+        context.copy_from_slice(&okm[32..]);
+        Ok(Self {
+            secret_key: Secret::from(secret_key),
+            context,
+        })
+    }
+
+    pub fn secret_key(&self) -> StaticSecret {
+        StaticSecret::from(*self.secret_key.expose())
+    }
+
+    pub fn token(&self) -> InviteToken {
+        InviteToken {
+            public_key: PublicKey::from(&self.secret_key()),
+            context: self.context,
+        }
+    }
+}
+
+impl Default for Invite {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The secret of the channel between the invite's creator and the party
+/// that answered it. Both sides compute it from their own secret key and
+/// the other's public key.
+pub fn shared_secret(
+    secret_key: &StaticSecret,
+    public_key: &PublicKey,
+    token: &InviteToken,
+) -> anyhow::Result<Secret> {
+    let shared = secret_key.diffie_hellman(public_key);
+    if !shared.was_contributory() {
+        bail!("Non-contributory key agreement");
+    }
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&token.context), shared.as_bytes())
+        .expand_multi_info(&[b"fchan invite", token.public_key.as_bytes()], &mut secret)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    Ok(Secret::from(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_roundtrip() {
+        let token = Invite::new().token();
+        let encoded = token.to_string();
+        assert!(encoded.starts_with(URI_PREFIX));
+        assert_eq!(encoded.parse::<InviteToken>().unwrap(), token);
+        assert_eq!(
+            encoded[URI_PREFIX.len()..].parse::<InviteToken>().unwrap(),
+            token,
+        );
+        assert!(encoded[..encoded.len() - 2].parse::<InviteToken>().is_err());
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let invite =
+            Invite::from_passphrase("correct horse battery staple", b"fchan test").unwrap();
+        let same = Invite::from_passphrase("correct horse battery staple", b"fchan test").unwrap();
+        assert_eq!(invite.token(), same.token());
+        assert_ne!(
+            invite.token(),
+            Invite::from_passphrase("correct horse battery staple", b"fchan other")
+                .unwrap()
+                .token(),
+        );
+
+        let token = invite.token();
+        let answer = StaticSecret::random_from_rng(OsRng);
+        let creator_secret =
+            shared_secret(&invite.secret_key(), &PublicKey::from(&answer), &token).unwrap();
+        let answer_secret = shared_secret(&answer, &token.public_key, &token).unwrap();
+        assert_eq!(creator_secret.expose(), answer_secret.expose());
+    }
+}
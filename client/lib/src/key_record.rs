@@ -0,0 +1,158 @@
+use anyhow::bail;
+use x25519_dalek::PublicKey;
+
+use crate::channel::{ChannelFeature, ChannelParameters, ChannelVersion};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyAlgorithm {
+    X25519,
+    Unknown(String),
+}
+
+impl KeyAlgorithm {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::X25519 => "x25519",
+            Self::Unknown(name) => name,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "x25519" => Self::X25519,
+            _ => Self::Unknown(name.to_string()),
+        }
+    }
+}
+
+/// A public key as published in the key registry, along with the channel
+/// versions and features that its owner's client supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRecord {
+    pub algorithm: KeyAlgorithm,
+    pub public_key: Vec<u8>,
+    pub protocol_versions: Vec<u32>,
+    pub features: Vec<String>,
+}
+
+impl KeyRecord {
+    /// Advertised by owners who publish a KEM prekey, so that a bundle
+    /// without one is not mistaken for an older client's.
+    pub const KEM_PREKEYS: &'static str = "kem_prekeys";
+
+    /// A record advertising everything this client supports, except the
+    /// [`ChannelFeature::OPT_IN`] features.
+    pub fn x25519(public_key: &PublicKey) -> Self {
+        Self {
+            algorithm: KeyAlgorithm::X25519,
+            public_key: public_key.as_bytes().to_vec(),
+            protocol_versions: ChannelVersion::SUPPORTED
+                .iter()
+                .map(|v| v.to_u32())
+                .collect(),
+            features: ChannelFeature::SUPPORTED
+                .iter()
+                .filter(|f| !ChannelFeature::OPT_IN.contains(f))
+                .map(|f| f.name().to_string())
+                .chain([Self::KEM_PREKEYS.to_string()])
+                .collect(),
+        }
+    }
+
+    pub fn has_kem_prekeys(&self) -> bool {
+        self.features.iter().any(|f| f == Self::KEM_PREKEYS)
+    }
+
+    pub fn with_feature(mut self, feature: ChannelFeature) -> Self {
+        if !self.channel_features().any(|f| f == feature) {
+            self.features.push(feature.name().to_string());
+        }
+        self
+    }
+
+    pub fn x25519_public_key(&self) -> anyhow::Result<PublicKey> {
+        if self.algorithm != KeyAlgorithm::X25519 {
+            bail!("Unsupported key algorithm {}", self.algorithm.name());
+        }
+        let public_key: [u8; 32] = match self.public_key.as_slice().try_into() {
+            Ok(a) => a,
+            Err(_) => bail!("Invalid key length {}", self.public_key.len()),
+        };
+        Ok(public_key.into())
+    }
+
+    pub fn channel_versions(&self) -> impl Iterator<Item = ChannelVersion> + '_ {
+        self.protocol_versions
+            .iter()
+            .filter_map(|v| ChannelVersion::from_u32(*v))
+    }
+
+    pub fn channel_features(&self) -> impl Iterator<Item = ChannelFeature> + '_ {
+        self.features
+            .iter()
+            .filter_map(|f| ChannelFeature::from_name(f))
+    }
+
+    /// Picks the newest channel version and the features that both records
+    /// support.
+    pub fn negotiate(&self, other: &KeyRecord) -> anyhow::Result<ChannelParameters> {
+        let other_versions = other.channel_versions().collect::<Vec<_>>();
+        let Some(version) = self
+            .channel_versions()
+            .filter(|v| other_versions.contains(v))
+            .max()
+        else {
+            bail!("No common protocol version");
+        };
+
+        let other_features = other.channel_features().collect::<Vec<_>>();
+        let mut features = self
+            .channel_features()
+            .filter(|f| other_features.contains(f))
+            .collect::<Vec<_>>();
+        features.sort();
+        features.dedup();
+
+        Ok(ChannelParameters { version, features })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(protocol_versions: &[u32], features: &[&str]) -> KeyRecord {
+        KeyRecord {
+            algorithm: KeyAlgorithm::X25519,
+            public_key: vec![0; 32],
+            protocol_versions: protocol_versions.to_vec(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn negotiate_ignores_unknown_versions_and_features() {
+        let ours = record(&[0, 99], &["ratchet", "filter_sync"]);
+        let theirs = record(&[99, 0], &["ratchet", "something_new"]);
+
+        assert_eq!(
+            ours.negotiate(&theirs).unwrap(),
+            ChannelParameters {
+                version: ChannelVersion::V0,
+                features: vec![ChannelFeature::Ratchet],
+            },
+        );
+    }
+
+    #[test]
+    fn negotiate_fails_without_common_version() {
+        assert!(record(&[0], &[]).negotiate(&record(&[99], &[])).is_err());
+    }
+
+    #[test]
+    fn unknown_algorithm_is_rejected() {
+        let mut r = record(&[0], &[]);
+        r.algorithm = KeyAlgorithm::from_name("x448");
+        assert!(r.x25519_public_key().is_err());
+    }
+}
@@ -1,19 +1,220 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::bail;
 use data_encoding::BASE64;
+use ed25519_dalek::{Signature, VerifyingKey};
 use near_primitives::{
     transaction::{Action, FunctionCallAction},
     types::AccountId,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::wallet::{Wallet, ONE_NEAR, ONE_TERAGAS};
+use crate::{
+    key_record::{KeyAlgorithm, KeyRecord},
+    prekey::{OneTimePrekey, PrekeyBundle, SignedKemPrekey, SignedPrekey},
+    wallet::{Wallet, ONE_NEAR, ONE_TERAGAS},
+};
+
+const SNAPSHOT_PAGE_SIZE: u64 = 500;
+/// Must match the contract; whatever is not spent is refunded.
+const PREKEY_CLAIM_FEE: u128 = ONE_NEAR / 1000;
 
 fn public_key_to_string(public_key: &x25519_dalek::PublicKey) -> String {
     BASE64.encode(public_key.as_bytes())
 }
 
+fn decode(encoded: &str) -> anyhow::Result<Vec<u8>> {
+    match BASE64.decode(encoded.as_bytes()) {
+        Ok(v) => Ok(v),
+        Err(e) => bail!("Could not decode: {}", e),
+    }
+}
+
+fn decode_array<const N: usize>(encoded: &str) -> anyhow::Result<[u8; N]> {
+    match decode(encoded)?.try_into() {
+        Ok(a) => Ok(a),
+        Err(e) => bail!("Invalid key length {}", e.len()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyRecordBase64 {
+    pub algorithm: String,
+    pub public_key: String,
+    pub protocol_versions: Vec<u32>,
+    pub features: Vec<String>,
+}
+
+impl From<&KeyRecord> for KeyRecordBase64 {
+    fn from(value: &KeyRecord) -> Self {
+        Self {
+            algorithm: value.algorithm.name().to_string(),
+            public_key: BASE64.encode(&value.public_key),
+            protocol_versions: value.protocol_versions.clone(),
+            features: value.features.clone(),
+        }
+    }
+}
+
+impl TryFrom<KeyRecordBase64> for KeyRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(value: KeyRecordBase64) -> Result<Self, Self::Error> {
+        Ok(Self {
+            algorithm: KeyAlgorithm::from_name(&value.algorithm),
+            public_key: decode(&value.public_key)?,
+            protocol_versions: value.protocol_versions,
+            features: value.features,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyRecordEntryBase64 {
+    pub account_id: AccountId,
+    pub record: KeyRecordBase64,
+}
+
+/// A local copy of every record in the registry. Looking contacts up here
+/// does not reveal to the RPC whose key we are interested in.
+#[derive(Debug, Clone)]
+pub struct RegistrySnapshot {
+    records: HashMap<AccountId, KeyRecord>,
+    downloaded_at: Instant,
+}
+
+impl RegistrySnapshot {
+    /// Whether the snapshot was downloaded longer than `max_age` ago, and may
+    /// be missing keys registered or changed since.
+    pub fn is_older_than(&self, max_age: Duration) -> bool {
+        self.downloaded_at.elapsed() > max_age
+    }
+
+    pub fn get(&self, account_id: &AccountId) -> Option<&KeyRecord> {
+        self.records.get(account_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignedPrekeyBase64 {
+    pub id: u32,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl From<&SignedPrekey> for SignedPrekeyBase64 {
+    fn from(value: &SignedPrekey) -> Self {
+        Self {
+            id: value.id,
+            public_key: public_key_to_string(&value.public_key),
+            signature: BASE64.encode(&value.signature.to_bytes()),
+        }
+    }
+}
+
+impl TryFrom<SignedPrekeyBase64> for SignedPrekey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SignedPrekeyBase64) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: value.id,
+            public_key: decode_array::<32>(&value.public_key)?.into(),
+            signature: Signature::from_bytes(&decode_array::<64>(&value.signature)?),
+        })
+    }
+}
+
+impl From<&SignedKemPrekey> for SignedPrekeyBase64 {
+    fn from(value: &SignedKemPrekey) -> Self {
+        Self {
+            id: value.id,
+            public_key: BASE64.encode(&value.public_key),
+            signature: BASE64.encode(&value.signature.to_bytes()),
+        }
+    }
+}
+
+impl TryFrom<SignedPrekeyBase64> for SignedKemPrekey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SignedPrekeyBase64) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: value.id,
+            public_key: decode(&value.public_key)?,
+            signature: Signature::from_bytes(&decode_array::<64>(&value.signature)?),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OneTimePrekeyBase64 {
+    pub id: u32,
+    pub public_key: String,
+}
+
+impl From<&OneTimePrekey> for OneTimePrekeyBase64 {
+    fn from(value: &OneTimePrekey) -> Self {
+        Self {
+            id: value.id,
+            public_key: public_key_to_string(&value.public_key),
+        }
+    }
+}
+
+impl TryFrom<OneTimePrekeyBase64> for OneTimePrekey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: OneTimePrekeyBase64) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: value.id,
+            public_key: decode_array::<32>(&value.public_key)?.into(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PrekeyBundleBase64 {
+    pub identity_key: String,
+    pub signing_key: String,
+    pub signed_prekey: SignedPrekeyBase64,
+    pub one_time_prekey: Option<OneTimePrekeyBase64>,
+    #[serde(default)]
+    pub kem_prekey: Option<SignedPrekeyBase64>,
+}
+
+impl TryFrom<PrekeyBundleBase64> for PrekeyBundle {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PrekeyBundleBase64) -> Result<Self, Self::Error> {
+        Ok(Self {
+            identity_key: decode_array::<32>(&value.identity_key)?.into(),
+            signing_key: VerifyingKey::from_bytes(&decode_array::<32>(&value.signing_key)?)?,
+            signed_prekey: value.signed_prekey.try_into()?,
+            one_time_prekey: value.one_time_prekey.map(TryInto::try_into).transpose()?,
+            kem_prekey: value.kem_prekey.map(TryInto::try_into).transpose()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SigningKeyRecordBase64 {
+    pub identity_key: String,
+    pub signing_key: String,
+}
+
 pub struct KeyRegistry {
     wallet: Arc<Wallet>,
     account_id: AccountId,
@@ -49,14 +250,147 @@ impl KeyRegistry {
         Ok(response)
     }
 
-    pub async fn set_my_key(&self, public_key: &x25519_dalek::PublicKey) -> anyhow::Result<()> {
+    pub async fn get_keys_for(
+        &self,
+        account_ids: &[AccountId],
+    ) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+        let response: Vec<Option<String>> = self
+            .wallet
+            .view(
+                self.account_id.clone(),
+                "get_public_keys",
+                json!({ "account_ids": account_ids }),
+            )
+            .await?;
+
+        response
+            .iter()
+            .map(|key| key.as_deref().map(decode).transpose())
+            .collect()
+    }
+
+    pub async fn get_key_record_for(
+        &self,
+        account_id: &AccountId,
+    ) -> anyhow::Result<Option<KeyRecord>> {
+        let response: Option<KeyRecordBase64> = self
+            .wallet
+            .view(
+                self.account_id.clone(),
+                "get_key_record",
+                json!({ "account_id": account_id }),
+            )
+            .await?;
+
+        response.map(TryInto::try_into).transpose()
+    }
+
+    pub async fn get_key_records_for(
+        &self,
+        account_ids: &[AccountId],
+    ) -> anyhow::Result<Vec<Option<KeyRecord>>> {
+        let response: Vec<Option<KeyRecordBase64>> = self
+            .wallet
+            .view(
+                self.account_id.clone(),
+                "get_key_records",
+                json!({ "account_ids": account_ids }),
+            )
+            .await?;
+
+        response
+            .into_iter()
+            .map(|record| record.map(TryInto::try_into).transpose())
+            .collect()
+    }
+
+    /// The signing key of `account_id`, and the key it was published for.
+    pub async fn get_signing_key_for(
+        &self,
+        account_id: &AccountId,
+    ) -> anyhow::Result<Option<(x25519_dalek::PublicKey, VerifyingKey)>> {
+        let response: Option<SigningKeyRecordBase64> = self
+            .wallet
+            .view(
+                self.account_id.clone(),
+                "get_signing_key",
+                json!({ "account_id": account_id }),
+            )
+            .await?;
+
+        let Some(record) = response else {
+            return Ok(None);
+        };
+        Ok(Some((
+            decode_array::<32>(&record.identity_key)?.into(),
+            VerifyingKey::from_bytes(&decode_array::<32>(&record.signing_key)?)?,
+        )))
+    }
+
+    pub async fn get_key_count(&self) -> anyhow::Result<u64> {
+        let response: String = self
+            .wallet
+            .view(self.account_id.clone(), "get_public_key_count", json!({}))
+            .await?;
+
+        Ok(response.parse()?)
+    }
+
+    pub async fn list_key_records(
+        &self,
+        from_index: u64,
+        limit: u64,
+    ) -> anyhow::Result<Vec<(AccountId, KeyRecord)>> {
+        let response: Vec<KeyRecordEntryBase64> = self
+            .wallet
+            .view(
+                self.account_id.clone(),
+                "list_key_records",
+                json!({
+                    "from_index": from_index.to_string(),
+                    "limit": limit.to_string(),
+                }),
+            )
+            .await?;
+
+        response
+            .into_iter()
+            .map(|entry| Ok((entry.account_id, entry.record.try_into()?)))
+            .collect()
+    }
+
+    /// Downloads the whole registry page by page. Keys removed while the
+    /// download is in progress may cause other entries to be skipped, so
+    /// snapshots should be refreshed periodically.
+    pub async fn download_snapshot(&self) -> anyhow::Result<RegistrySnapshot> {
+        let mut records = HashMap::new();
+        let mut from_index = 0;
+
+        loop {
+            let page = self
+                .list_key_records(from_index, SNAPSHOT_PAGE_SIZE)
+                .await?;
+            if page.is_empty() {
+                break;
+            }
+            from_index += page.len() as u64;
+            records.extend(page);
+        }
+
+        Ok(RegistrySnapshot {
+            records,
+            downloaded_at: Instant::now(),
+        })
+    }
+
+    pub async fn set_my_key_record(&self, record: &KeyRecord) -> anyhow::Result<()> {
         self.wallet
             .transact(
                 self.account_id.clone(),
                 vec![Action::FunctionCall(Box::new(FunctionCallAction {
-                    method_name: "set_public_key".to_string(),
+                    method_name: "set_key_record".to_string(),
                     args: json!({
-                        "public_key": public_key_to_string(public_key),
+                        "record": KeyRecordBase64::from(record),
                     })
                     .to_string()
                     .into_bytes(),
@@ -68,4 +402,94 @@ impl KeyRegistry {
 
         Ok(())
     }
+
+    pub async fn set_my_prekeys(
+        &self,
+        signing_key: &VerifyingKey,
+        signed_prekey: &SignedPrekey,
+        kem_prekey: &SignedKemPrekey,
+        one_time_prekeys: &[OneTimePrekey],
+    ) -> anyhow::Result<()> {
+        self.wallet
+            .transact(
+                self.account_id.clone(),
+                vec![Action::FunctionCall(Box::new(FunctionCallAction {
+                    method_name: "set_prekeys".to_string(),
+                    args: json!({
+                        "signing_key": BASE64.encode(signing_key.as_bytes()),
+                        "signed_prekey": SignedPrekeyBase64::from(signed_prekey),
+                        "kem_prekey": SignedPrekeyBase64::from(kem_prekey),
+                        "one_time_prekeys": one_time_prekeys
+                            .iter()
+                            .map(OneTimePrekeyBase64::from)
+                            .collect::<Vec<_>>(),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    gas: 30 * ONE_TERAGAS,
+                    deposit: ONE_NEAR / 2,
+                }))],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_my_one_time_prekeys(
+        &self,
+        one_time_prekeys: &[OneTimePrekey],
+    ) -> anyhow::Result<()> {
+        self.wallet
+            .transact(
+                self.account_id.clone(),
+                vec![Action::FunctionCall(Box::new(FunctionCallAction {
+                    method_name: "add_one_time_prekeys".to_string(),
+                    args: json!({
+                        "one_time_prekeys": one_time_prekeys
+                            .iter()
+                            .map(OneTimePrekeyBase64::from)
+                            .collect::<Vec<_>>(),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    gas: 30 * ONE_TERAGAS,
+                    deposit: ONE_NEAR / 2,
+                }))],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_my_one_time_prekey_count(&self) -> anyhow::Result<u32> {
+        self.wallet
+            .view(
+                self.account_id.clone(),
+                "get_one_time_prekey_count",
+                json!({ "account_id": self.wallet.account_id }),
+            )
+            .await
+    }
+
+    /// Consumes one of the account's one-time prekeys, so this is a
+    /// transaction rather than a view call.
+    pub async fn claim_prekey_bundle(
+        &self,
+        account_id: &AccountId,
+    ) -> anyhow::Result<Option<PrekeyBundle>> {
+        let response: Option<PrekeyBundleBase64> = self
+            .wallet
+            .call(
+                self.account_id.clone(),
+                vec![Action::FunctionCall(Box::new(FunctionCallAction {
+                    method_name: "claim_prekey_bundle".to_string(),
+                    args: json!({ "account_id": account_id }).to_string().into_bytes(),
+                    gas: 10 * ONE_TERAGAS,
+                    deposit: PREKEY_CLAIM_FEE,
+                }))],
+            )
+            .await?;
+
+        response.map(TryInto::try_into).transpose()
+    }
 }
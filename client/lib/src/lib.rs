@@ -1,10 +1,21 @@
 pub mod channel;
 pub mod combined;
+pub mod contacts;
+pub mod conversation;
 pub mod group;
+pub mod group_invite;
+pub mod inbox;
+pub mod invite;
+pub mod key_record;
 pub mod key_registry;
 pub mod message;
 pub mod message_repository;
 pub mod messenger;
+pub mod prekey;
+pub mod ratchet;
+pub mod secret;
+pub mod tree_kem;
+pub mod trust;
 pub mod wallet;
 
 #[cfg(test)]
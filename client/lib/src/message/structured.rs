@@ -1,11 +1,36 @@
-// TODO: Message commands (invite to group, etc.)
+use crate::{
+    conversation::ChannelMoved,
+    group_invite::GroupInvite,
+    prekey::SessionInit,
+    tree_kem::{Commit, Welcome},
+};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Structured {
     Text(String),
+    SessionInit(SessionInit),
+    ChannelMoved(Box<ChannelMoved>),
+    /// Sets how long keys for unread messages are kept in the channel it is
+    /// sent to. The latest setting from any member applies.
+    DisappearingMessages(Option<u64>),
+    /// Only ever sent through a direct channel, since it contains the group
+    /// secret.
+    GroupInvite(Box<GroupInvite>),
+    /// Only ever posted to a tree group's control channel.
+    TreeCommit(Box<Commit>),
+    /// Only ever sent through a direct channel, to a member added to a tree
+    /// group.
+    TreeWelcome(Box<Welcome>),
 }
 
 impl Structured {
     const DISC_TEXT: u32 = 1;
+    const DISC_SESSION_INIT: u32 = 2;
+    const DISC_CHANNEL_MOVED: u32 = 3;
+    const DISC_DISAPPEARING_MESSAGES: u32 = 4;
+    const DISC_GROUP_INVITE: u32 = 5;
+    const DISC_TREE_COMMIT: u32 = 6;
+    const DISC_TREE_WELCOME: u32 = 7;
 
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
@@ -15,19 +40,94 @@ impl Structured {
                 buf.extend(u32::to_le_bytes(Self::DISC_TEXT));
                 buf.extend(text.as_bytes());
 
+                buf
+            }
+            Self::SessionInit(session_init) => {
+                let session_init = session_init.to_bytes();
+                let mut buf = Vec::with_capacity(4 + session_init.len());
+
+                buf.extend(u32::to_le_bytes(Self::DISC_SESSION_INIT));
+                buf.extend(session_init);
+
+                buf
+            }
+            Self::ChannelMoved(channel_moved) => {
+                let channel_moved = channel_moved.to_bytes();
+                let mut buf = Vec::with_capacity(4 + channel_moved.len());
+
+                buf.extend(u32::to_le_bytes(Self::DISC_CHANNEL_MOVED));
+                buf.extend(channel_moved);
+
+                buf
+            }
+            Self::DisappearingMessages(retention_ms) => {
+                let mut buf = Vec::with_capacity(4 + 8);
+
+                buf.extend(u32::to_le_bytes(Self::DISC_DISAPPEARING_MESSAGES));
+                // Zero turns disappearing messages off.
+                buf.extend(u64::to_le_bytes(retention_ms.unwrap_or(0)));
+
+                buf
+            }
+            Self::GroupInvite(group_invite) => {
+                let group_invite = group_invite.to_bytes();
+                let mut buf = Vec::with_capacity(4 + group_invite.len());
+
+                buf.extend(u32::to_le_bytes(Self::DISC_GROUP_INVITE));
+                buf.extend(group_invite);
+
+                buf
+            }
+            Self::TreeCommit(commit) => {
+                let commit = commit.to_bytes();
+                let mut buf = Vec::with_capacity(4 + commit.len());
+
+                buf.extend(u32::to_le_bytes(Self::DISC_TREE_COMMIT));
+                buf.extend(commit);
+
+                buf
+            }
+            Self::TreeWelcome(welcome) => {
+                let welcome = welcome.to_bytes();
+                let mut buf = Vec::with_capacity(4 + welcome.len());
+
+                buf.extend(u32::to_le_bytes(Self::DISC_TREE_WELCOME));
+                buf.extend(welcome);
+
                 buf
             }
         }
     }
 
     pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
-        let discriminant = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let discriminant = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?);
 
         match discriminant {
             Self::DISC_TEXT => {
                 let text = String::from_utf8(bytes[4..].to_vec()).ok()?;
                 Some(Self::Text(text))
             }
+            Self::DISC_SESSION_INIT => {
+                SessionInit::try_from_bytes(&bytes[4..]).map(Self::SessionInit)
+            }
+            Self::DISC_CHANNEL_MOVED => {
+                ChannelMoved::try_from_bytes(&bytes[4..]).map(|m| Self::ChannelMoved(Box::new(m)))
+            }
+            Self::DISC_DISAPPEARING_MESSAGES => {
+                let retention_ms = u64::from_le_bytes(bytes[4..].try_into().ok()?);
+                Some(Self::DisappearingMessages(
+                    (retention_ms > 0).then_some(retention_ms),
+                ))
+            }
+            Self::DISC_GROUP_INVITE => {
+                GroupInvite::try_from_bytes(&bytes[4..]).map(|i| Self::GroupInvite(Box::new(i)))
+            }
+            Self::DISC_TREE_COMMIT => {
+                Commit::try_from_bytes(&bytes[4..]).map(|c| Self::TreeCommit(Box::new(c)))
+            }
+            Self::DISC_TREE_WELCOME => {
+                Welcome::try_from_bytes(&bytes[4..]).map(|w| Self::TreeWelcome(Box::new(w)))
+            }
             _ => None,
         }
     }
@@ -8,6 +8,7 @@ use near_primitives::{
 };
 use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::OnceCell;
 
 use crate::wallet::{Wallet, ONE_NEAR, ONE_TERAGAS};
 
@@ -27,6 +28,7 @@ pub struct EncryptedMessageBase64 {
 pub struct MessageRepository {
     wallet: Arc<Wallet>,
     account_id: AccountId,
+    network: OnceCell<String>,
 }
 
 impl MessageRepository {
@@ -34,9 +36,24 @@ impl MessageRepository {
         Self {
             wallet,
             account_id: account_id.clone(),
+            network: OnceCell::new(),
         }
     }
 
+    pub fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+
+    /// The chain ID of the network the repository is deployed on. Fetched
+    /// once and then cached.
+    pub async fn network(&self) -> anyhow::Result<&str> {
+        let network = self
+            .network
+            .get_or_try_init(|| self.wallet.chain_id())
+            .await?;
+        Ok(network)
+    }
+
     pub async fn get_message(
         &self,
         sequence_hash: &[u8],
@@ -1,22 +1,140 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::bail;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
 use near_primitives::types::AccountId;
-use tokio::sync::RwLock; // TODO: can we remove?
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, RwLock}; // TODO: can we remove?
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
 
 use crate::{
-    channel::CorrespondentId, group::Group, key_registry::KeyRegistry,
-    message_repository::MessageRepository, wallet::Wallet,
+    channel::{ChannelFeature, ChannelParameters, ChannelVersion, CorrespondentId},
+    contacts::{Contact, ContactRequest, ContactStatus, ContactStore},
+    conversation::{ChannelMoved, Conversation},
+    group::Group,
+    group_invite::{self, GroupInvite, GroupPolicy},
+    inbox,
+    invite::{self, Invite, InviteToken},
+    key_record::KeyRecord,
+    key_registry::{KeyRegistry, RegistrySnapshot},
+    message::{
+        chunk::ChunkedWriteStream,
+        stream::{ReadStream, WriteStream},
+        structured::Structured,
+    },
+    message_repository::MessageRepository,
+    prekey::{self, PrekeyStore, SessionInit},
+    ratchet::{DoubleRatchet, SenderChains},
+    secret::Secret,
+    tree_kem::{self, TreeGroup, Welcome},
+    trust::{self, KeyChange, KeyObservation, TrustStore},
+    wallet::Wallet,
 };
 
+const CONTROL_CHUNK_SIZE: usize = 256;
+const ONE_TIME_PREKEY_POOL_SIZE: u32 = 50;
+const KEY_LOOKUP_BATCH_SIZE: usize = 100;
+const REGISTRY_SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+fn derive_signing_key(secret_key: &StaticSecret) -> SigningKey {
+    let mut seed = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, secret_key.as_bytes())
+        .expand(b"fchan signing key", seed.as_mut_slice())
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    SigningKey::from_bytes(&seed)
+}
+
+/// How the secret of a direct message session was agreed on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAgreement {
+    /// We sent the [`prekey::SessionInit`] with this hash.
+    Initiated([u8; 32]),
+    /// We answered the correspondent's [`prekey::SessionInit`] with this
+    /// hash.
+    Responded([u8; 32]),
+    /// The correspondent had no prekeys, so only the identity keys were used.
+    /// Without forward secrecy, and never with
+    /// [`Messenger::without_static_key_agreement_fallback`].
+    Static,
+}
+
+#[derive(Clone)]
+struct Session {
+    shared_secret: Secret,
+    parameters: ChannelParameters,
+    double_ratchet: Option<Arc<Mutex<DoubleRatchet>>>,
+    sender_chains: Option<Arc<Mutex<SenderChains>>>,
+    /// `None` for sessions saved before this was recorded.
+    key_agreement: Option<KeyAgreement>,
+    /// Hash of the correspondent's latest init that we have acted on.
+    seen_init: Option<[u8; 32]>,
+}
+
+/// Our key and the correspondent's key, since a session is only valid for the
+/// pair it was agreed on.
+type SessionKey = (CorrespondentId, CorrespondentId);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredSession {
+    /// `None` for sessions saved before this was recorded, which are assumed
+    /// to have been agreed on with our current key.
+    #[serde(default)]
+    own_id: Option<CorrespondentId>,
+    correspondent_id: CorrespondentId,
+    shared_secret: Secret,
+    parameters: ChannelParameters,
+    double_ratchet: Option<DoubleRatchet>,
+    #[serde(default)]
+    sender_chains: Option<SenderChains>,
+    #[serde(default)]
+    key_agreement: Option<KeyAgreement>,
+    #[serde(default)]
+    seen_init: Option<[u8; 32]>,
+}
+
+/// Established direct message sessions, including ratchet state, and the
+/// prekeys that sessions are initiated with. Contains secrets, so store it
+/// accordingly.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionStore {
+    sessions: Vec<StoredSession>,
+    #[serde(default)]
+    prekeys: Option<PrekeyStore>,
+}
+
 pub struct Messenger {
+    account_id: AccountId,
     secret_key: StaticSecret,
+    signing_key: SigningKey,
     key_registry: KeyRegistry,
-    correspondent_map: Arc<RwLock<HashMap<CorrespondentId, AccountId>>>,
+    registry_snapshot: RwLock<Option<RegistrySnapshot>>,
+    prekey_store: RwLock<PrekeyStore>,
+    sessions: RwLock<HashMap<SessionKey, Session>>,
+    static_key_agreement_fallback: bool,
+    double_ratchet: bool,
+    trust_store: RwLock<TrustStore>,
+    pending_key_changes: Mutex<Vec<KeyChange>>,
+    correspondent_map: Arc<RwLock<HashMap<CorrespondentId, Contact>>>,
+    contacts: RwLock<ContactStore>,
+    inbox_poll: Mutex<()>,
     pub message_repository: Arc<MessageRepository>,
 }
 
+/// Posts a control message to `channel`. Reads to the end first so that the
+/// write position is past every message already in the channel.
+async fn send_control(channel: Group, message: Vec<u8>) -> anyhow::Result<()> {
+    let channel = Arc::new(channel);
+    let stream = channel.read_stream();
+    while stream.receive_next().await?.is_some() {}
+    ChunkedWriteStream::new(&*channel, CONTROL_CHUNK_SIZE)
+        .send(message)
+        .await
+}
+
 impl Messenger {
     pub fn new(
         wallet: Arc<Wallet>,
@@ -27,13 +145,24 @@ impl Messenger {
         let mut correspondent_map = HashMap::new();
         correspondent_map.insert(
             PublicKey::from(&messenger_secret_key).to_bytes().into(),
-            wallet.account_id.clone(),
+            Contact::Account(wallet.account_id.clone()),
         );
 
         Self {
+            account_id: wallet.account_id.clone(),
+            signing_key: derive_signing_key(&messenger_secret_key),
             secret_key: messenger_secret_key,
             key_registry: KeyRegistry::new(Arc::clone(&wallet), key_registry_account_id),
+            registry_snapshot: RwLock::new(None),
+            prekey_store: RwLock::new(PrekeyStore::new()),
+            sessions: RwLock::new(HashMap::new()),
+            static_key_agreement_fallback: true,
+            double_ratchet: false,
+            trust_store: RwLock::new(TrustStore::default()),
+            pending_key_changes: Mutex::new(vec![]),
             correspondent_map: Arc::new(RwLock::new(correspondent_map)),
+            contacts: RwLock::new(ContactStore::default()),
+            inbox_poll: Mutex::new(()),
             message_repository: Arc::new(MessageRepository::new(
                 Arc::clone(&wallet),
                 message_repository_account_id,
@@ -41,50 +170,1428 @@ impl Messenger {
         }
     }
 
-    pub async fn resolve_correspondent_id(
-        &self,
-        correspondent_id: &CorrespondentId,
-    ) -> Option<AccountId> {
+    /// Refuses to open a direct channel with correspondents who have not
+    /// published prekeys, instead of falling back to the static key
+    /// agreement. Without this, such sessions can be told apart with
+    /// [`Messenger::key_agreement`].
+    pub fn without_static_key_agreement_fallback(mut self) -> Self {
+        self.static_key_agreement_fallback = false;
+        self
+    }
+
+    /// Advertises [`ChannelFeature::DoubleRatchet`], so that direct channels
+    /// with correspondents who advertise it as well use a Double Ratchet.
+    /// Its state only survives a restart if the sessions are saved with
+    /// [`Messenger::export_sessions`].
+    pub fn with_double_ratchet(mut self) -> Self {
+        self.double_ratchet = true;
+        self
+    }
+
+    /// Correspondents whose account we have not seen are known by their key.
+    pub async fn resolve_correspondent_id(&self, correspondent_id: &CorrespondentId) -> Contact {
         self.correspondent_map
             .read()
             .await
             .get(correspondent_id)
             .cloned()
+            .unwrap_or_else(|| Contact::Key(**correspondent_id))
     }
 
     pub fn public_key(&self) -> PublicKey {
         PublicKey::from(&self.secret_key)
     }
 
+    pub fn signing_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn key_record(&self) -> KeyRecord {
+        let key_record = KeyRecord::x25519(&self.public_key());
+        if self.double_ratchet {
+            key_record.with_feature(ChannelFeature::DoubleRatchet)
+        } else {
+            key_record
+        }
+    }
+
     pub async fn sync_key(&self) -> anyhow::Result<()> {
         self.key_registry
-            .set_my_key(&PublicKey::from(&self.secret_key))
+            .set_my_key_record(&self.key_record())
+            .await?;
+        self.publish_prekeys().await
+    }
+
+    /// Replaces the signed prekey and the whole one-time prekey pool in the
+    /// key registry. The secrets of the replaced one-time prekeys are kept
+    /// until used, since they may have been claimed already. Prekeys only
+    /// survive a restart if they are saved with [`Messenger::export_sessions`].
+    pub async fn publish_prekeys(&self) -> anyhow::Result<()> {
+        let mut prekey_store = self.prekey_store.write().await;
+        let one_time_prekeys =
+            prekey_store.generate_one_time_prekeys(ONE_TIME_PREKEY_POOL_SIZE as usize);
+        let signed_prekey = prekey_store.signed_prekey(&self.public_key(), &self.signing_key);
+        let kem_prekey = prekey_store.kem_prekey(&self.public_key(), &self.signing_key);
+
+        self.key_registry
+            .set_my_prekeys(
+                &self.signing_key(),
+                &signed_prekey,
+                &kem_prekey,
+                &one_time_prekeys,
+            )
             .await
     }
 
-    pub async fn direct_message(&self, account_id: &AccountId) -> anyhow::Result<Group> {
-        let correspondent_public_key = self.key_registry.get_key_for(account_id).await?;
-        let correspondent_public_key: [u8; 32] = match correspondent_public_key.try_into() {
-            Ok(a) => a,
-            Err(e) => bail!("Invalid key length {}", e.len()),
+    /// Tops up the one-time prekey pool if fewer than `minimum` remain.
+    pub async fn replenish_prekeys(&self, minimum: u32) -> anyhow::Result<()> {
+        let count = self.key_registry.get_my_one_time_prekey_count().await?;
+        if count >= minimum {
+            return Ok(());
+        }
+
+        let one_time_prekeys = self
+            .prekey_store
+            .write()
+            .await
+            .generate_one_time_prekeys(ONE_TIME_PREKEY_POOL_SIZE.saturating_sub(count) as usize);
+
+        self.key_registry
+            .add_my_one_time_prekeys(&one_time_prekeys)
+            .await
+    }
+
+    /// Downloads the whole key registry. Afterwards, correspondents' keys are
+    /// looked up locally instead of with one view call per contact.
+    pub async fn sync_registry_snapshot(&self) -> anyhow::Result<()> {
+        let snapshot = self.key_registry.download_snapshot().await?;
+        *self.registry_snapshot.write().await = Some(snapshot);
+        Ok(())
+    }
+
+    /// Looks `account_id` up in the registry snapshot, if there is one, which
+    /// is downloaded again once it is too old. Accounts missing from the
+    /// snapshot, e.g. because they registered since, are looked up directly.
+    async fn get_key_record_for(&self, account_id: &AccountId) -> anyhow::Result<KeyRecord> {
+        let expired = self
+            .registry_snapshot
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|s| s.is_older_than(REGISTRY_SNAPSHOT_MAX_AGE));
+        if expired {
+            self.sync_registry_snapshot().await?;
+        }
+
+        let cached = self
+            .registry_snapshot
+            .read()
+            .await
+            .as_ref()
+            .and_then(|s| s.get(account_id).cloned());
+        let record = match cached {
+            Some(record) => Some(record),
+            None => self.key_registry.get_key_record_for(account_id).await?,
         };
-        let correspondent_id: CorrespondentId = correspondent_public_key.into();
-        self.correspondent_map
+
+        match record {
+            Some(record) => Ok(record),
+            None => bail!("No key registered for {account_id}"),
+        }
+    }
+
+    /// Whether `public_key` is the key registered for `account_id`. Asks the
+    /// registry itself if the snapshot disagrees, since it may be stale, and
+    /// only fails if the registry cannot be reached.
+    async fn is_registered_key(
+        &self,
+        account_id: &AccountId,
+        public_key: &PublicKey,
+    ) -> anyhow::Result<bool> {
+        let cached = self
+            .registry_snapshot
+            .read()
+            .await
+            .as_ref()
+            .and_then(|s| s.get(account_id).cloned());
+        if cached.and_then(|r| r.x25519_public_key().ok()) == Some(*public_key) {
+            return Ok(true);
+        }
+        let record = self.key_registry.get_key_record_for(account_id).await?;
+        Ok(record.and_then(|r| r.x25519_public_key().ok()) == Some(*public_key))
+    }
+
+    pub async fn import_trust_store(&self, trust_store: TrustStore) {
+        *self.trust_store.write().await = trust_store;
+    }
+
+    pub async fn export_trust_store(&self) -> TrustStore {
+        self.trust_store.read().await.clone()
+    }
+
+    pub async fn import_sessions(&self, session_store: SessionStore) {
+        if let Some(prekeys) = session_store.prekeys {
+            *self.prekey_store.write().await = prekeys;
+        }
+        let own_id: CorrespondentId = self.public_key().to_bytes().into();
+        *self.sessions.write().await = session_store
+            .sessions
+            .into_iter()
+            .map(|s| {
+                (
+                    (
+                        s.own_id.unwrap_or_else(|| own_id.clone()),
+                        s.correspondent_id,
+                    ),
+                    Session {
+                        shared_secret: s.shared_secret,
+                        parameters: s.parameters,
+                        double_ratchet: s.double_ratchet.map(|r| Arc::new(Mutex::new(r))),
+                        sender_chains: s.sender_chains.map(|c| Arc::new(Mutex::new(c))),
+                        key_agreement: s.key_agreement,
+                        seen_init: s.seen_init,
+                    },
+                )
+            })
+            .collect();
+    }
+
+    pub async fn export_sessions(&self) -> SessionStore {
+        let mut sessions = vec![];
+        for ((own_id, correspondent_id), session) in self.sessions.read().await.iter() {
+            let double_ratchet = match &session.double_ratchet {
+                Some(state) => Some(state.lock().await.clone()),
+                None => None,
+            };
+            let sender_chains = match &session.sender_chains {
+                Some(state) => Some(state.lock().await.clone()),
+                None => None,
+            };
+            sessions.push(StoredSession {
+                own_id: Some(own_id.clone()),
+                correspondent_id: correspondent_id.clone(),
+                shared_secret: session.shared_secret.clone(),
+                parameters: session.parameters.clone(),
+                double_ratchet,
+                sender_chains,
+                key_agreement: session.key_agreement,
+                seen_init: session.seen_init,
+            });
+        }
+        SessionStore {
+            sessions,
+            prekeys: Some(self.prekey_store.read().await.clone()),
+        }
+    }
+
+    pub async fn import_contacts(&self, contacts: ContactStore) {
+        *self.contacts.write().await = contacts;
+    }
+
+    pub async fn export_contacts(&self) -> ContactStore {
+        self.contacts.read().await.clone()
+    }
+
+    async fn observe_key(&self, account_id: &AccountId, public_key: &PublicKey) {
+        let observation = self
+            .trust_store
             .write()
             .await
-            .insert(correspondent_id, account_id.clone());
-        let shared_secret = self
-            .secret_key
-            .diffie_hellman(&correspondent_public_key.into())
-            .to_bytes();
-        let group = Group::new(
+            .observe(account_id, public_key);
+        if let KeyObservation::Changed { previous } = observation {
+            self.pending_key_changes.lock().await.push(KeyChange {
+                account_id: account_id.clone(),
+                previous_key: previous.public_key.into(),
+                new_key: *public_key,
+                was_verified: previous.verified,
+            });
+        }
+    }
+
+    /// Re-reads the keys of every known contact and returns the ones that
+    /// have changed since they were last seen, including changes noticed by
+    /// [`Messenger::direct_message`].
+    pub async fn poll_key_changes(&self) -> anyhow::Result<Vec<KeyChange>> {
+        let account_ids = self
+            .trust_store
+            .read()
+            .await
+            .account_ids()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let has_snapshot = self.registry_snapshot.read().await.is_some();
+        if has_snapshot {
+            self.sync_registry_snapshot().await?;
+        }
+
+        for batch in account_ids.chunks(KEY_LOOKUP_BATCH_SIZE) {
+            let records = match self.registry_snapshot.read().await.as_ref() {
+                Some(snapshot) => batch.iter().map(|a| snapshot.get(a).cloned()).collect(),
+                None => self.key_registry.get_key_records_for(batch).await?,
+            };
+
+            for (account_id, record) in batch.iter().zip(records) {
+                if let Some(Ok(public_key)) = record.map(|r| r.x25519_public_key()) {
+                    self.observe_key(account_id, &public_key).await;
+                }
+            }
+        }
+
+        Ok(self.pending_key_changes.lock().await.drain(..).collect())
+    }
+
+    pub async fn safety_number(&self, account_id: &AccountId) -> Option<String> {
+        let trusted_key = self.trust_store.read().await.get(account_id)?.public_key;
+        Some(trust::safety_number(
+            &self.public_key(),
+            &trusted_key.into(),
+        ))
+    }
+
+    /// Marks the contact's current key as verified, e.g. after comparing
+    /// safety numbers in person.
+    pub async fn verify_contact(&self, account_id: &AccountId) -> bool {
+        self.trust_store.write().await.mark_verified(account_id)
+    }
+
+    pub async fn is_contact_verified(&self, account_id: &AccountId) -> bool {
+        self.trust_store
+            .read()
+            .await
+            .get(account_id)
+            .is_some_and(|k| k.verified)
+    }
+
+    async fn handshake_channel(
+        &self,
+        correspondent_public_key: &PublicKey,
+        version: ChannelVersion,
+    ) -> anyhow::Result<Group> {
+        Ok(Group::new(
             Arc::clone(&self.message_repository),
+            self.message_repository.network().await?,
             self.public_key().to_bytes().into(),
-            vec![correspondent_public_key.into()],
+            vec![correspondent_public_key.to_bytes().into()],
+            Secret::from(
+                self.secret_key
+                    .diffie_hellman(correspondent_public_key)
+                    .to_bytes(),
+            ),
+            b"handshake",
+            ChannelParameters {
+                version,
+                features: vec![],
+            },
+        ))
+    }
+
+    /// The correspondent's latest init in the handshake channel.
+    async fn latest_session_init(
+        &self,
+        correspondent_public_key: &PublicKey,
+        version: ChannelVersion,
+    ) -> anyhow::Result<Option<SessionInit>> {
+        let correspondent_id: CorrespondentId = correspondent_public_key.to_bytes().into();
+        let handshake = Arc::new(
+            self.handshake_channel(correspondent_public_key, version)
+                .await?,
+        );
+
+        let stream = handshake.read_stream();
+        let mut latest = None;
+        while let Some((sender, message)) = stream.receive_next().await? {
+            if sender != correspondent_id {
+                continue;
+            }
+            if let Some(Structured::SessionInit(session_init)) =
+                Structured::try_from_bytes(&message.bytes)
+            {
+                latest = Some(session_init);
+            }
+        }
+        Ok(latest)
+    }
+
+    fn new_session(
+        &self,
+        correspondent_id: &CorrespondentId,
+        shared_secret: Secret,
+        parameters: ChannelParameters,
+        key_agreement: KeyAgreement,
+        seen_init: Option<[u8; 32]>,
+    ) -> Session {
+        let double_ratchet = parameters
+            .has_feature(ChannelFeature::DoubleRatchet)
+            .then(|| {
+                Arc::new(Mutex::new(DoubleRatchet::new(
+                    &shared_secret,
+                    &self.public_key().to_bytes().into(),
+                    correspondent_id,
+                )))
+            });
+        // A Double Ratchet takes the place of the sender ratchets.
+        let sender_chains = (double_ratchet.is_none()
+            && parameters.has_feature(ChannelFeature::Ratchet))
+        .then(|| Arc::new(Mutex::new(SenderChains::default())));
+        Session {
             shared_secret,
+            parameters,
+            double_ratchet,
+            sender_chains,
+            key_agreement: Some(key_agreement),
+            seen_init,
+        }
+    }
+
+    /// Keeps `cached`, or replaces it with a new session, so that both sides
+    /// end up with the same one whenever either opens the channel:
+    /// - the correspondent's latest init is answered if we have not seen it,
+    ///   unless we initiated our session, their init does not say that it
+    ///   replaces ours, and our key is lower, which settles inits sent at
+    ///   the same time;
+    /// - an init that we cannot answer, e.g. because its prekeys were lost,
+    ///   is replaced by one of ours that says so.
+    async fn settle_session(
+        &self,
+        account_id: &AccountId,
+        correspondent_public_key: &PublicKey,
+        parameters: ChannelParameters,
+        require_kem_prekey: bool,
+        cached: Option<Session>,
+    ) -> anyhow::Result<Session> {
+        let correspondent_id: CorrespondentId = correspondent_public_key.to_bytes().into();
+        let latest = self
+            .latest_session_init(correspondent_public_key, parameters.version)
+            .await?;
+        let latest_hash = latest.as_ref().map(SessionInit::hash);
+
+        if let Some(mut session) = cached {
+            if latest_hash.is_none() || latest_hash == session.seen_init {
+                return Ok(session);
+            }
+            let superseded = match (session.key_agreement, &latest) {
+                (Some(KeyAgreement::Initiated(ours)), Some(theirs)) => {
+                    theirs.replaces == Some(ours)
+                        || correspondent_public_key.as_bytes() < self.public_key().as_bytes()
+                }
+                (Some(_), _) => true,
+                // Sessions saved before inits were tracked only take note
+                // of the init, so that it is not answered a second time.
+                (None, _) => false,
+            };
+            if !superseded {
+                session.seen_init = latest_hash;
+                return Ok(session);
+            }
+        }
+
+        if let (Some(session_init), Some(hash)) = (&latest, latest_hash) {
+            let answered = self.prekey_store.write().await.respond(
+                &self.secret_key,
+                correspondent_public_key,
+                session_init,
+            );
+            if let Ok(secret) = answered {
+                return Ok(self.new_session(
+                    &correspondent_id,
+                    secret,
+                    parameters,
+                    KeyAgreement::Responded(hash),
+                    latest_hash,
+                ));
+            }
+        }
+
+        let Some(bundle) = self.key_registry.claim_prekey_bundle(account_id).await? else {
+            if !self.static_key_agreement_fallback {
+                bail!("{account_id} has not published prekeys");
+            }
+            let secret = Secret::from(
+                self.secret_key
+                    .diffie_hellman(correspondent_public_key)
+                    .to_bytes(),
+            );
+            return Ok(self.new_session(
+                &correspondent_id,
+                secret,
+                parameters,
+                KeyAgreement::Static,
+                latest_hash,
+            ));
+        };
+
+        if &bundle.identity_key != correspondent_public_key {
+            bail!("Prekey bundle does not match the registered key");
+        }
+
+        let (mut session_init, secret) =
+            prekey::initiate(&self.secret_key, &bundle, require_kem_prekey)?;
+        session_init.replaces = latest_hash;
+        let hash = session_init.hash();
+
+        let handshake = self
+            .handshake_channel(correspondent_public_key, parameters.version)
+            .await?;
+        send_control(handshake, Structured::SessionInit(session_init).to_bytes()).await?;
+
+        Ok(self.new_session(
+            &correspondent_id,
+            secret,
+            parameters,
+            KeyAgreement::Initiated(hash),
+            latest_hash,
+        ))
+    }
+
+    async fn direct_channel(
+        &self,
+        correspondent_id: CorrespondentId,
+        session: &Session,
+    ) -> anyhow::Result<Group> {
+        let group = Group::new(
+            Arc::clone(&self.message_repository),
+            self.message_repository.network().await?,
+            self.public_key().to_bytes().into(),
+            vec![correspondent_id],
+            session.shared_secret.clone(),
             &[2], // no context for direct message (?)
+            session.parameters.clone(),
+        );
+        let group = if session
+            .parameters
+            .has_feature(ChannelFeature::SenderAuthentication)
+        {
+            group.with_sender_authentication(&self.secret_key)
+        } else {
+            group
+        };
+
+        Ok(match (&session.double_ratchet, &session.sender_chains) {
+            (Some(state), _) => group.with_double_ratchet(Arc::clone(state)).await,
+            (None, Some(state)) => group.with_sender_chains(Arc::clone(state)).await,
+            (None, None) => group,
+        })
+    }
+
+    /// Binds the signing key that `account_id` publishes with their prekeys to
+    /// `public_key` in the trust store, while `public_key` is current, so
+    /// that a later [`ChannelMoved`] away from it can be verified.
+    async fn learn_signing_key(
+        &self,
+        account_id: &AccountId,
+        public_key: &PublicKey,
+    ) -> anyhow::Result<()> {
+        let known = self
+            .trust_store
+            .read()
+            .await
+            .signing_key(account_id, public_key.as_bytes())
+            .is_some();
+        if known {
+            return Ok(());
+        }
+        if let Some((identity_key, signing_key)) =
+            self.key_registry.get_signing_key_for(account_id).await?
+        {
+            if identity_key == *public_key {
+                self.trust_store.write().await.bind_signing_key(
+                    account_id,
+                    public_key,
+                    &signing_key,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn session_key(&self, correspondent_id: &CorrespondentId) -> SessionKey {
+        (
+            self.public_key().to_bytes().into(),
+            correspondent_id.clone(),
+        )
+    }
+
+    /// Opens the direct channel with `account_id`, which also accepts them as
+    /// a contact. Fails if they are blocked.
+    pub async fn direct_message(&self, account_id: &AccountId) -> anyhow::Result<Group> {
+        let correspondent_record = self.get_key_record_for(account_id).await?;
+        let correspondent_public_key = correspondent_record.x25519_public_key()?;
+        {
+            let mut contacts = self.contacts.write().await;
+            if contacts.status(account_id) == ContactStatus::Blocked
+                || contacts.is_key_blocked(correspondent_public_key.as_bytes())
+            {
+                bail!("{account_id} is blocked");
+            }
+            contacts.accept(account_id);
+        }
+        let parameters = self.key_record().negotiate(&correspondent_record)?;
+        let correspondent_id: CorrespondentId = correspondent_public_key.to_bytes().into();
+        self.observe_key(account_id, &correspondent_public_key)
+            .await;
+        self.correspondent_map.write().await.insert(
+            correspondent_id.clone(),
+            Contact::Account(account_id.clone()),
         );
 
+        self.learn_signing_key(account_id, &correspondent_public_key)
+            .await?;
+
+        let session_key = self.session_key(&correspondent_id);
+        let cached_session = self.sessions.read().await.get(&session_key).cloned();
+        let session = self
+            .settle_session(
+                account_id,
+                &correspondent_public_key,
+                parameters,
+                correspondent_record.has_kem_prekeys(),
+                cached_session,
+            )
+            .await?;
+        self.sessions
+            .write()
+            .await
+            .insert(session_key, session.clone());
+
+        self.direct_channel(correspondent_id, &session).await
+    }
+
+    /// How the session with `account_id` was agreed on, if there is one.
+    pub async fn key_agreement(&self, account_id: &AccountId) -> Option<KeyAgreement> {
+        let public_key = self.trust_store.read().await.get(account_id)?.public_key;
+        let session_key = self.session_key(&public_key.into());
+        self.sessions.read().await.get(&session_key)?.key_agreement
+    }
+
+    /// Opens a channel with the holder of `public_key` without looking it up
+    /// in the key registry, e.g. for a correspondent who only shared their
+    /// fingerprint. With no key record to negotiate with, the channel uses
+    /// fixed parameters and the static key agreement, so it is separate from
+    /// the one [`Messenger::direct_message`] opens, and both sides have to
+    /// open it by key.
+    pub async fn direct_message_to_key(&self, public_key: &PublicKey) -> anyhow::Result<Group> {
+        if self
+            .contacts
+            .read()
+            .await
+            .is_key_blocked(public_key.as_bytes())
+        {
+            bail!("{} is blocked", Contact::Key(public_key.to_bytes()));
+        }
+        let shared = self.secret_key.diffie_hellman(public_key);
+        if !shared.was_contributory() {
+            bail!("Non-contributory key agreement");
+        }
+
+        let correspondent_id: CorrespondentId = public_key.to_bytes().into();
+        self.correspondent_map
+            .write()
+            .await
+            .entry(correspondent_id.clone())
+            .or_insert_with(|| Contact::Key(public_key.to_bytes()));
+
+        Ok(Group::new(
+            Arc::clone(&self.message_repository),
+            self.message_repository.network().await?,
+            self.public_key().to_bytes().into(),
+            vec![correspondent_id],
+            Secret::from(shared.to_bytes()),
+            b"key",
+            invite::parameters(),
+        ))
+    }
+
+    /// Like [`Messenger::direct_message`], but also includes the channels
+    /// used with the contact's previous keys, as far as they are known.
+    pub async fn conversation(&self, account_id: &AccountId) -> anyhow::Result<Conversation> {
+        let current = Arc::new(self.direct_message(account_id).await?);
+        let (previous_keys, signing_keys) = self
+            .trust_store
+            .read()
+            .await
+            .get(account_id)
+            .map(|k| (k.previous_keys.clone(), k.signing_keys.clone()))
+            .unwrap_or_default();
+        let signing_keys = signing_keys
+            .into_iter()
+            .filter_map(|(key, signing_key)| {
+                Some((key.into(), VerifyingKey::from_bytes(&signing_key).ok()?))
+            })
+            .collect();
+
+        let sessions = self.sessions.read().await;
+        let mut correspondent_map = self.correspondent_map.write().await;
+        let mut channels = vec![];
+        for previous_key in previous_keys {
+            let correspondent_id: CorrespondentId = previous_key.into();
+            if let Some(session) = sessions.get(&self.session_key(&correspondent_id)) {
+                correspondent_map.insert(
+                    correspondent_id.clone(),
+                    Contact::Account(account_id.clone()),
+                );
+                channels.push(Arc::new(
+                    self.direct_channel(correspondent_id, session).await?,
+                ));
+            }
+        }
+        channels.push(current);
+
+        Ok(Conversation::new(channels).with_signing_keys(signing_keys))
+    }
+
+    /// Posts a signed [`ChannelMoved`] to the direct channel of every known
+    /// contact. Call this before publishing `new_public_key`, while the old
+    /// channels can still be written to. Returns the contacts it could not
+    /// be posted to.
+    pub async fn announce_key_rotation(
+        &self,
+        new_public_key: &PublicKey,
+    ) -> anyhow::Result<Vec<AccountId>> {
+        let moved = Structured::ChannelMoved(Box::new(ChannelMoved::new(
+            &self.public_key(),
+            new_public_key,
+            &self.signing_key,
+        )))
+        .to_bytes();
+
+        let account_ids = self
+            .trust_store
+            .read()
+            .await
+            .account_ids()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut failed = vec![];
+        for account_id in account_ids {
+            if self.contact_status(&account_id).await == ContactStatus::Blocked {
+                continue;
+            }
+            let sent = match self.direct_message(&account_id).await {
+                Ok(channel) => send_control(channel, moved.clone()).await,
+                Err(e) => Err(e),
+            };
+            if sent.is_err() {
+                failed.push(account_id);
+            }
+        }
+
+        Ok(failed)
+    }
+
+    /// The keys that `members` are trusted with, opening a direct channel
+    /// with each of them.
+    async fn member_keys(&self, members: &[AccountId]) -> anyhow::Result<Vec<(PublicKey, Group)>> {
+        let mut member_keys = vec![];
+        for account_id in members {
+            let channel = self.direct_message(account_id).await?;
+            // unwrap ok because opening the direct channel records the key
+            let trusted_key = self
+                .trust_store
+                .read()
+                .await
+                .get(account_id)
+                .unwrap()
+                .public_key;
+            member_keys.push((PublicKey::from(trusted_key), channel));
+        }
+        Ok(member_keys)
+    }
+
+    /// Creates a group with us and `members`, and sends each member a signed
+    /// [`GroupInvite`] through their direct channel. The returned invite is
+    /// how we open the group later, and contains its secret, so store it
+    /// accordingly.
+    pub async fn create_group(
+        &self,
+        name: &str,
+        members: &[AccountId],
+    ) -> anyhow::Result<GroupInvite> {
+        self.create_group_with_policy(name, members, GroupPolicy::default())
+            .await
+    }
+
+    /// Like [`Messenger::create_group`], with `policy` and us as an admin,
+    /// unless `policy` is the default.
+    pub async fn create_group_with_policy(
+        &self,
+        name: &str,
+        members: &[AccountId],
+        policy: GroupPolicy,
+    ) -> anyhow::Result<GroupInvite> {
+        let (member_keys, channels): (Vec<_>, Vec<_>) =
+            self.member_keys(members).await?.into_iter().unzip();
+
+        let invite = GroupInvite::new_with_policy(
+            &self.public_key(),
+            &self.signing_key,
+            member_keys,
+            name,
+            policy,
+        )?;
+        let message = Structured::GroupInvite(Box::new(invite.clone())).to_bytes();
+        for channel in channels {
+            send_control(channel, message.clone()).await?;
+        }
+
+        Ok(invite)
+    }
+
+    /// The direct channel with the holder of `public_key`, through their
+    /// account if we know it.
+    async fn member_channel(&self, public_key: &PublicKey) -> anyhow::Result<Group> {
+        let correspondent_id: CorrespondentId = public_key.to_bytes().into();
+        match self.resolve_correspondent_id(&correspondent_id).await {
+            Contact::Account(account_id) => self.direct_message(&account_id).await,
+            Contact::Key(_) => self.direct_message_to_key(public_key).await,
+        }
+    }
+
+    /// The hash of the generation after `invite`, if a member has published
+    /// one, and the first attempt after it. Entries that cannot be opened are
+    /// skipped.
+    async fn group_change(&self, invite: &GroupInvite) -> anyhow::Result<(Option<[u8; 32]>, u32)> {
+        let mut attempt = 0;
+        loop {
+            let Some(sealed) = self
+                .message_repository
+                .get_message(&*invite.change_slot(attempt))
+                .await?
+            else {
+                return Ok((None, attempt));
+            };
+            if let Some(hash) = invite.open_change(&sealed.message, attempt) {
+                return Ok((Some(hash), attempt + 1));
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Starts the next generation of the group with `members`, `name` and
+    /// `policy`, and sends its invite to each member. Fails without sending
+    /// anything if the group's policy does not allow us to make the change,
+    /// or if another member changed the group first.
+    async fn change_group(
+        &self,
+        invite: &GroupInvite,
+        members: Vec<PublicKey>,
+        name: &str,
+        policy: GroupPolicy,
+    ) -> anyhow::Result<GroupInvite> {
+        if !invite.members.contains(&self.public_key()) {
+            bail!("Not a member of group {}", invite.name);
+        }
+
+        let next =
+            invite.next_with(&self.public_key(), &self.signing_key, members, name, policy)?;
+        invite.verify_next(&next)?;
+
+        // Claimed before it is sent, so that two members changing the group
+        // at the same time do not split it.
+        let (published, attempt) = self.group_change(invite).await?;
+        if published.is_none() {
+            let sealed = invite.seal_change(&next, attempt)?;
+            // Losing the race is noticed below.
+            let _ = self
+                .message_repository
+                .publish_message(&*invite.change_slot(attempt), &sealed)
+                .await;
+        }
+        if self.group_change(invite).await?.0 != Some(next.hash()) {
+            bail!("Group {} was changed by another member first", invite.name);
+        }
+
+        let message = Structured::GroupInvite(Box::new(next.clone())).to_bytes();
+        for member in &next.members {
+            if member != &self.public_key() {
+                let channel = self.member_channel(member).await?;
+                send_control(channel, message.clone()).await?;
+            }
+        }
+
+        Ok(next)
+    }
+
+    /// Adds `account_id` to the group. Returns the invite of the new
+    /// generation, which the new member can only read from.
+    pub async fn add_group_member(
+        &self,
+        invite: &GroupInvite,
+        account_id: &AccountId,
+    ) -> anyhow::Result<GroupInvite> {
+        self.direct_message(account_id).await?;
+        // unwrap ok because opening the direct channel records the key
+        let trusted_key = self
+            .trust_store
+            .read()
+            .await
+            .get(account_id)
+            .unwrap()
+            .public_key;
+
+        let mut members = invite.members.clone();
+        members.push(trusted_key.into());
+        self.change_group(invite, members, &invite.name, invite.policy.clone())
+            .await
+    }
+
+    /// Removes `member` from the group. Returns the invite of the new
+    /// generation, whose secret is only sent to the remaining members.
+    pub async fn remove_group_member(
+        &self,
+        invite: &GroupInvite,
+        member: &PublicKey,
+    ) -> anyhow::Result<GroupInvite> {
+        if !invite.members.contains(member) {
+            bail!("Not a member of group {}", invite.name);
+        }
+
+        let members = invite
+            .members
+            .iter()
+            .filter(|m| *m != member)
+            .copied()
+            .collect();
+        self.change_group(invite, members, &invite.name, invite.policy.clone())
+            .await
+    }
+
+    /// Renames the group, if its policy allows us to.
+    pub async fn rename_group(
+        &self,
+        invite: &GroupInvite,
+        name: &str,
+    ) -> anyhow::Result<GroupInvite> {
+        self.change_group(invite, invite.members.clone(), name, invite.policy.clone())
+            .await
+    }
+
+    /// Replaces the group's policy, e.g. to change who is an admin. Only
+    /// admins may do this.
+    pub async fn set_group_policy(
+        &self,
+        invite: &GroupInvite,
+        policy: GroupPolicy,
+    ) -> anyhow::Result<GroupInvite> {
+        self.change_group(invite, invite.members.clone(), &invite.name, policy)
+            .await
+    }
+
+    /// Opens the group channel described by `invite`, which must include us.
+    pub async fn open_group(&self, invite: &GroupInvite) -> anyhow::Result<Group> {
+        let own_id: CorrespondentId = self.public_key().to_bytes().into();
+        let member_ids = invite.member_ids();
+        if !member_ids.contains(&own_id) {
+            bail!("Not a member of group {}", invite.name);
+        }
+
+        Ok(Group::new(
+            Arc::clone(&self.message_repository),
+            self.message_repository.network().await?,
+            own_id.clone(),
+            member_ids.into_iter().filter(|m| m != &own_id).collect(),
+            invite.secret.clone(),
+            &invite.context(),
+            group_invite::parameters(invite.version),
+        )
+        .with_sender_authentication(&self.secret_key))
+    }
+
+    async fn check_group_invite_sender(
+        &self,
+        sender: &CorrespondentId,
+        invite: &GroupInvite,
+    ) -> anyhow::Result<()> {
+        if invite.creator.as_bytes() != &**sender {
+            bail!("Group invite was not sent by its creator");
+        }
+        if self
+            .contacts
+            .read()
+            .await
+            .is_key_blocked(invite.creator.as_bytes())
+        {
+            bail!("Group invite from a blocked key");
+        }
+        Ok(())
+    }
+
+    /// Checks an invite received from `sender` through a direct channel and
+    /// opens its group. Only the creator may send it. For a group we are
+    /// already in, use [`Messenger::accept_group_change`] instead, which also
+    /// checks that the change was made by a member.
+    pub async fn accept_group_invite(
+        &self,
+        sender: &CorrespondentId,
+        invite: &GroupInvite,
+    ) -> anyhow::Result<Group> {
+        invite.verify()?;
+        self.check_group_invite_sender(sender, invite).await?;
+        self.open_group(invite).await
+    }
+
+    /// Like [`Messenger::accept_group_invite`], for the generation after
+    /// `current`. Fails if the group's policy did not allow its creator to
+    /// make the change, or if it lost to a change made at the same time.
+    pub async fn accept_group_change(
+        &self,
+        current: &GroupInvite,
+        sender: &CorrespondentId,
+        next: &GroupInvite,
+    ) -> anyhow::Result<Group> {
+        current.verify_next(next)?;
+        self.check_group_invite_sender(sender, next).await?;
+        match self.group_change(current).await?.0 {
+            Some(hash) if hash == next.hash() => {}
+            Some(_) => bail!("Group {} was changed by another member first", current.name),
+            None => bail!("Change of group {} has not been published", current.name),
+        }
+        self.open_group(next).await
+    }
+
+    /// Reads every generation of a group that we are a member of, oldest
+    /// first, so that messages sent to a generation after the next one had
+    /// started are not lost. Messages should be sent to the newest
+    /// generation.
+    pub async fn group_conversation(
+        &self,
+        invites: &[GroupInvite],
+    ) -> anyhow::Result<Conversation> {
+        let mut invites = invites.iter().collect::<Vec<_>>();
+        invites.sort_by_key(|i| i.generation);
+
+        let mut channels = vec![];
+        for invite in invites {
+            if invite.members.contains(&self.public_key()) {
+                channels.push(Arc::new(self.open_group(invite).await?));
+            }
+        }
+        if channels.is_empty() {
+            bail!("Not a member of any generation of this group");
+        }
+
+        Ok(Conversation::new(channels))
+    }
+
+    /// Creates a tree group with us and `members`, and sends each member a
+    /// [`Welcome`] through their direct channel. The returned group contains
+    /// secrets, so store it accordingly.
+    pub async fn create_tree_group(
+        &self,
+        name: &str,
+        members: &[AccountId],
+    ) -> anyhow::Result<TreeGroup> {
+        let (member_keys, channels): (Vec<_>, Vec<_>) =
+            self.member_keys(members).await?.into_iter().unzip();
+
+        let (group, welcome) = TreeGroup::create(&self.secret_key, name, &member_keys)?;
+        let message = Structured::TreeWelcome(Box::new(welcome)).to_bytes();
+        for channel in channels {
+            send_control(channel, message.clone()).await?;
+        }
+
         Ok(group)
     }
+
+    /// Joins the tree group of a welcome received from `sender` through a
+    /// direct channel. Only the member who made its commit may send it.
+    pub async fn accept_tree_welcome(
+        &self,
+        sender: &CorrespondentId,
+        welcome: &Welcome,
+    ) -> anyhow::Result<TreeGroup> {
+        let Some(committer) = welcome.committer() else {
+            bail!("Welcome has no committer");
+        };
+        if committer.as_bytes() != &**sender {
+            bail!("Welcome was not sent by its committer");
+        }
+        if self
+            .contacts
+            .read()
+            .await
+            .is_key_blocked(committer.as_bytes())
+        {
+            bail!("Welcome from a blocked key");
+        }
+        TreeGroup::join(&self.secret_key, welcome)
+    }
+
+    /// Applies the commits posted to the group's control channel since the
+    /// last call. Returns `true` if the group has moved to a later epoch.
+    /// Entries that cannot be opened or are not valid commits are skipped.
+    /// Once a commit has removed us, [`TreeGroup::is_removed`] is set and
+    /// nothing more is read.
+    pub async fn sync_tree_group(&self, group: &mut TreeGroup) -> anyhow::Result<bool> {
+        let epoch = group.epoch();
+        while !group.is_removed() {
+            let Some(sealed) = self
+                .message_repository
+                .get_message(&*group.control_slot())
+                .await?
+            else {
+                break;
+            };
+            let commit = match group
+                .open_control(&sealed.message)
+                .map(|m| Structured::try_from_bytes(&m))
+            {
+                Ok(Some(Structured::TreeCommit(commit))) => commit,
+                _ => {
+                    group.skip_control_slot();
+                    continue;
+                }
+            };
+            if group.process(&self.secret_key, &commit).is_err() {
+                group.skip_control_slot();
+            }
+        }
+        Ok(group.epoch() != epoch)
+    }
+
+    /// Adds `added` to and removes `removed` from the group in one commit,
+    /// which costs a single transaction however large the group is, plus a
+    /// [`Welcome`] for each added member. Fails, leaving the group as it
+    /// was, if another member committed first; sync and try again.
+    pub async fn change_tree_group(
+        &self,
+        group: &mut TreeGroup,
+        added: &[AccountId],
+        removed: &[PublicKey],
+    ) -> anyhow::Result<()> {
+        self.sync_tree_group(group).await?;
+        let (added_keys, channels): (Vec<_>, Vec<_>) =
+            self.member_keys(added).await?.into_iter().unzip();
+
+        let (commit, next, welcome) = group.commit(&self.secret_key, removed, &added_keys)?;
+        let message = Structured::TreeCommit(Box::new(commit)).to_bytes();
+        self.message_repository
+            .publish_message(&*group.control_slot(), &group.seal_control(&message)?)
+            .await?;
+        *group = next;
+
+        let message = Structured::TreeWelcome(Box::new(welcome)).to_bytes();
+        for channel in channels {
+            send_control(channel, message.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    fn open_tree_epoch(
+        &self,
+        network: &str,
+        group: &TreeGroup,
+        epoch: u64,
+        secret: &Secret,
+        members: &[PublicKey],
+    ) -> Group {
+        let own_id: CorrespondentId = self.public_key().to_bytes().into();
+        Group::new(
+            Arc::clone(&self.message_repository),
+            network,
+            own_id.clone(),
+            members
+                .iter()
+                .map(|m| CorrespondentId::from(m.to_bytes()))
+                .filter(|m| m != &own_id)
+                .collect(),
+            secret.clone(),
+            &group.context(epoch),
+            group_invite::parameters(tree_kem::CHANNEL_VERSION),
+        )
+        .with_sender_authentication(&self.secret_key)
+    }
+
+    /// The channel of the group's current epoch, which messages should be
+    /// sent to.
+    pub async fn tree_group_channel(&self, group: &TreeGroup) -> anyhow::Result<Group> {
+        if group.is_removed() {
+            bail!("Removed from group {}", group.name);
+        }
+        let network = self.message_repository.network().await?;
+        // unwrap ok because a group always has at least one epoch
+        let (epoch, secret, members) = group.epochs().last().unwrap();
+        Ok(self.open_tree_epoch(network, group, epoch, secret, &members))
+    }
+
+    /// Reads every epoch of the group that we have been a member in, oldest
+    /// first, like [`Messenger::group_conversation`].
+    pub async fn tree_group_conversation(&self, group: &TreeGroup) -> anyhow::Result<Conversation> {
+        let network = self.message_repository.network().await?;
+        let channels = group
+            .epochs()
+            .map(|(epoch, secret, members)| {
+                Arc::new(self.open_tree_epoch(network, group, epoch, secret, &members))
+            })
+            .collect();
+        Ok(Conversation::new(channels))
+    }
+
+    async fn is_inbox_slot_taken(&self, index: u64) -> anyhow::Result<bool> {
+        Ok(self
+            .message_repository
+            .get_message(&*inbox::inbox_slot(index))
+            .await?
+            .is_some())
+    }
+
+    /// A free slot right after the taken ones, found by galloping ahead and
+    /// then bisecting, rather than reading the inbox from the start. Probes
+    /// are randomized, so that entries planted far ahead cannot steer the
+    /// search past the point where readers stop.
+    async fn inbox_end(&self) -> anyhow::Result<u64> {
+        // Slot `low - 1` is taken (unless `low` is 0) and slot `high` is free.
+        let mut low = 0;
+        let mut step = 1;
+        let mut high = loop {
+            let probe = low + OsRng.gen_range(0..step);
+            if !self.is_inbox_slot_taken(probe).await? {
+                break probe;
+            }
+            low = probe + 1;
+            step *= 2;
+        };
+        while low < high {
+            let probe = OsRng.gen_range(low..high);
+            if self.is_inbox_slot_taken(probe).await? {
+                low = probe + 1;
+            } else {
+                high = probe;
+            }
+        }
+        Ok(high)
+    }
+
+    /// Posts a sealed [`inbox::FirstContact`] to the inbox for `account_id`,
+    /// so that they learn that we want to talk. Observers see that our
+    /// account's transaction wrote to the inbox, but not for whom, by which
+    /// key or what was said.
+    pub async fn send_first_contact(
+        &self,
+        account_id: &AccountId,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let recipient = self
+            .get_key_record_for(account_id)
+            .await?
+            .x25519_public_key()?;
+        let sealed = inbox::seal(&self.secret_key, &recipient, &self.account_id, message)?;
+
+        // Slots are write-once, so take the first free one, and move on if
+        // someone else takes it first.
+        let mut index = self.inbox_end().await?;
+        loop {
+            let slot = inbox::inbox_slot(index);
+            if self.message_repository.get_message(&*slot).await?.is_none() {
+                let published = self
+                    .message_repository
+                    .publish_message(&*slot, &sealed)
+                    .await;
+                // Publishing into a taken slot does not fail, so read it back.
+                match self.message_repository.get_message(&*slot).await? {
+                    Some(entry) if entry.message == sealed => return Ok(()),
+                    Some(_) => {}
+                    None => {
+                        published?;
+                        bail!("First contact to {account_id} was not published");
+                    }
+                }
+            }
+            index += 1;
+        }
+    }
+
+    /// Reads the entries posted to our inbox since the last call and returns
+    /// the new contact requests among them. Entries that are not for us or
+    /// lack their proof of work, whose key does not match the registered key
+    /// of the account they claim to be from, or whose sender is blocked,
+    /// accepted already or over the rate limit, are skipped. If a lookup
+    /// fails, the error is returned and the entry is read again next time.
+    pub async fn poll_inbox(&self) -> anyhow::Result<Vec<ContactRequest>> {
+        // Only one poll at a time, without holding the contacts across RPCs.
+        let _polling = self.inbox_poll.lock().await;
+        let mut requests = vec![];
+
+        loop {
+            let inbox_cursor = self.contacts.read().await.inbox_cursor();
+            let Some(entry) = self
+                .message_repository
+                .get_message(&*inbox::inbox_slot(inbox_cursor))
+                .await?
+            else {
+                return Ok(requests);
+            };
+
+            // The work is checked first, since it is cheap and rules out
+            // entries that were posted without paying for them.
+            let mut contact = match inbox::has_enough_work(&entry.message) {
+                true => inbox::open(&self.secret_key, &entry.message).ok(),
+                false => None,
+            };
+            // Checked before the registry lookup, so blocked senders cost
+            // nothing more than opening their entries.
+            if let Some(c) = &contact {
+                let contacts = self.contacts.read().await;
+                if contacts.status(&c.account_id) == ContactStatus::Blocked
+                    || contacts.is_key_blocked(c.sender_key.as_bytes())
+                {
+                    contact = None;
+                }
+            }
+            if let Some(c) = &contact {
+                if !self.is_registered_key(&c.account_id, &c.sender_key).await? {
+                    contact = None;
+                }
+            }
+
+            let mut contacts = self.contacts.write().await;
+            contacts.set_inbox_cursor(inbox_cursor + 1);
+            if let Some(contact) = contact {
+                let request = ContactRequest {
+                    account_id: contact.account_id,
+                    public_key: contact.sender_key.to_bytes(),
+                    message: contact.message,
+                    block_timestamp_ms: entry.block_timestamp_ms,
+                };
+                if contacts.receive(request.clone()) {
+                    requests.push(request);
+                }
+            }
+        }
+    }
+
+    pub async fn pending_contact_requests(&self) -> Vec<ContactRequest> {
+        self.contacts.read().await.pending().to_vec()
+    }
+
+    pub async fn contact_status(&self, account_id: &AccountId) -> ContactStatus {
+        self.contacts.read().await.status(account_id)
+    }
+
+    pub async fn accept_contact(&self, account_id: &AccountId) {
+        self.contacts.write().await.accept(account_id);
+    }
+
+    /// Blocks `account_id` and every key we know them by. Their inbox
+    /// entries are dropped and their channels are no longer opened.
+    pub async fn block_contact(&self, account_id: &AccountId) {
+        let mut public_keys = vec![];
+        if let Some(trusted_key) = self.trust_store.read().await.get(account_id) {
+            public_keys.push(trusted_key.public_key);
+            public_keys.extend(&trusted_key.previous_keys);
+        }
+        if let Ok(Ok(public_key)) = self
+            .get_key_record_for(account_id)
+            .await
+            .map(|r| r.x25519_public_key())
+        {
+            public_keys.push(public_key.to_bytes());
+        }
+        self.contacts.write().await.block(account_id, &public_keys);
+    }
+
+    /// Returns `false` if `account_id` was not blocked.
+    pub async fn unblock_contact(&self, account_id: &AccountId) -> bool {
+        self.contacts.write().await.unblock(account_id)
+    }
+
+    async fn invite_group(
+        &self,
+        secret_key: &StaticSecret,
+        correspondent_public_key: &PublicKey,
+        token: &InviteToken,
+    ) -> anyhow::Result<Group> {
+        Ok(Group::new(
+            Arc::clone(&self.message_repository),
+            self.message_repository.network().await?,
+            PublicKey::from(secret_key).to_bytes().into(),
+            vec![correspondent_public_key.to_bytes().into()],
+            invite::shared_secret(secret_key, correspondent_public_key, token)?,
+            b"invite",
+            invite::parameters(),
+        ))
+    }
+
+    async fn invite_answer(&self, token: &InviteToken) -> anyhow::Result<Option<PublicKey>> {
+        let Some(answer) = self
+            .message_repository
+            .get_message(&*token.answer_slot())
+            .await?
+        else {
+            return Ok(None);
+        };
+        match <[u8; 32]>::try_from(answer.message.as_slice()) {
+            Ok(public_key) => Ok(Some(public_key.into())),
+            Err(_) => bail!("Invalid invite answer"),
+        }
+    }
+
+    /// Answers an invite with our key and opens the channel to its creator.
+    /// Fails if someone else answered it first.
+    pub async fn accept_invite(&self, token: &InviteToken) -> anyhow::Result<Group> {
+        match self.invite_answer(token).await? {
+            Some(answer) if answer != self.public_key() => {
+                bail!("Invite has already been answered");
+            }
+            Some(_) => {}
+            None => {
+                self.message_repository
+                    .publish_message(&*token.answer_slot(), self.public_key().as_bytes())
+                    .await?;
+                // Publishing into a taken slot does not fail, so read it back.
+                if self.invite_answer(token).await? != Some(self.public_key()) {
+                    bail!("Invite has already been answered");
+                }
+            }
+        }
+
+        self.invite_group(&self.secret_key, &token.public_key, token)
+            .await
+    }
+
+    /// Opens the channel of an invite we created, once it has been answered.
+    pub async fn invite_channel(&self, invite: &Invite) -> anyhow::Result<Option<Group>> {
+        let token = invite.token();
+        match self.invite_answer(&token).await? {
+            Some(answer) => Ok(Some(
+                self.invite_group(&invite.secret_key(), &answer, &token)
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens a channel with whoever else knows `passphrase`. The first party
+    /// answers the invite derived from it, and the second opens it as its
+    /// creator.
+    pub async fn passphrase_channel(&self, passphrase: &str) -> anyhow::Result<Group> {
+        let salt = <Sha256 as Digest>::new()
+            .chain_update(b"fchan passphrase")
+            .chain_update(self.message_repository.network().await?)
+            .chain_update(self.message_repository.account_id().as_str())
+            .finalize();
+        let invite = Invite::from_passphrase(passphrase, &salt)?;
+        let token = invite.token();
+
+        let answer = match self.invite_answer(&token).await? {
+            Some(answer) => answer,
+            None => match self.accept_invite(&token).await {
+                Ok(group) => return Ok(group),
+                // The other party answered between our read and our write.
+                Err(e) => match self.invite_answer(&token).await? {
+                    Some(answer) => answer,
+                    None => return Err(e),
+                },
+            },
+        };
+
+        if answer == self.public_key() {
+            // We answered in an earlier run.
+            return self.accept_invite(&token).await;
+        }
+        self.invite_group(&invite.secret_key(), &answer, &token)
+            .await
+    }
 }
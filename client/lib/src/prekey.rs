@@ -0,0 +1,655 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::bail;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate},
+    Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::secret::Secret;
+
+type KemEncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+type KemDecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+pub const KEM_CIPHERTEXT_LENGTH: usize = 1088;
+/// One-time prekeys from pools that have since been replaced are kept until
+/// used, up to this many in total, in case an init that used one of them has
+/// not been read yet.
+const MAX_STORED_ONE_TIME_PREKEYS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl SignedPrekey {
+    fn signed_bytes(identity_key: &PublicKey, id: u32, public_key: &PublicKey) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + 4 + 32 + 32);
+        buf.extend(b"fchan prekey");
+        buf.extend(identity_key.as_bytes());
+        buf.extend(id.to_le_bytes());
+        buf.extend(public_key.as_bytes());
+        buf
+    }
+
+    pub fn verify(
+        &self,
+        identity_key: &PublicKey,
+        signing_key: &VerifyingKey,
+    ) -> anyhow::Result<()> {
+        let bytes = Self::signed_bytes(identity_key, self.id, &self.public_key);
+        if let Err(e) = signing_key.verify(&bytes, &self.signature) {
+            bail!("Invalid signed prekey signature: {e}");
+        }
+        Ok(())
+    }
+}
+
+/// An ML-KEM-768 encapsulation key, signed like [`SignedPrekey`]. The secret
+/// encapsulated to it is mixed into the session secret, so that ciphertexts
+/// recorded today stay confidential if x25519 is broken later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedKemPrekey {
+    pub id: u32,
+    pub public_key: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl SignedKemPrekey {
+    fn signed_bytes(identity_key: &PublicKey, id: u32, public_key: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + 32 + 4 + public_key.len());
+        buf.extend(b"fchan kem prekey");
+        buf.extend(identity_key.as_bytes());
+        buf.extend(id.to_le_bytes());
+        buf.extend(public_key);
+        buf
+    }
+
+    pub fn verify(
+        &self,
+        identity_key: &PublicKey,
+        signing_key: &VerifyingKey,
+    ) -> anyhow::Result<()> {
+        let bytes = Self::signed_bytes(identity_key, self.id, &self.public_key);
+        if let Err(e) = signing_key.verify(&bytes, &self.signature) {
+            bail!("Invalid KEM prekey signature: {e}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub public_key: PublicKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrekeyBundle {
+    pub identity_key: PublicKey,
+    pub signing_key: VerifyingKey,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekey: Option<OneTimePrekey>,
+    pub kem_prekey: Option<SignedKemPrekey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KemCiphertext {
+    pub prekey_id: u32,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Published by the initiator of a session so that the responder can repeat
+/// the key agreement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInit {
+    pub ephemeral_key: PublicKey,
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+    pub kem_ciphertext: Option<KemCiphertext>,
+    /// Hash of the correspondent's init that the sender saw but could not
+    /// answer, e.g. because its prekeys were lost in a restart. Tells the
+    /// correspondent to give up the session of that init for this one.
+    pub replaces: Option<[u8; 32]>,
+}
+
+impl SessionInit {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + 4 + 4 + 4 + KEM_CIPHERTEXT_LENGTH);
+        buf.extend(self.ephemeral_key.as_bytes());
+        buf.extend(self.signed_prekey_id.to_le_bytes());
+        if let Some(id) = self.one_time_prekey_id {
+            buf.extend(id.to_le_bytes());
+        }
+        if let Some(kem_ciphertext) = &self.kem_ciphertext {
+            buf.extend(kem_ciphertext.prekey_id.to_le_bytes());
+            buf.extend(&kem_ciphertext.ciphertext);
+        }
+        if let Some(replaces) = &self.replaces {
+            buf.extend(replaces);
+        }
+        buf
+    }
+
+    /// Every optional part has a different length, so which are present
+    /// follows from the total length.
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        let ephemeral_key: [u8; 32] = bytes.get(0..32)?.try_into().ok()?;
+        let signed_prekey_id = u32::from_le_bytes(bytes.get(32..36)?.try_into().ok()?);
+        let kem_length = 4 + KEM_CIPHERTEXT_LENGTH;
+        let optional_length = bytes.len() - 36;
+        let has_kem = optional_length >= kem_length;
+        let (has_one_time_prekey, has_replaces) =
+            match optional_length - if has_kem { kem_length } else { 0 } {
+                0 => (false, false),
+                4 => (true, false),
+                32 => (false, true),
+                36 => (true, true),
+                _ => return None,
+            };
+
+        let mut start = 36;
+        let one_time_prekey_id = if has_one_time_prekey {
+            start += 4;
+            Some(u32::from_le_bytes(bytes[36..40].try_into().ok()?))
+        } else {
+            None
+        };
+        let kem_ciphertext = if has_kem {
+            let kem_ciphertext = KemCiphertext {
+                prekey_id: u32::from_le_bytes(bytes[start..start + 4].try_into().ok()?),
+                ciphertext: bytes[start + 4..start + kem_length].to_vec(),
+            };
+            start += kem_length;
+            Some(kem_ciphertext)
+        } else {
+            None
+        };
+        let replaces = if has_replaces {
+            Some(bytes[start..].try_into().ok()?)
+        } else {
+            None
+        };
+
+        Some(Self {
+            ephemeral_key: ephemeral_key.into(),
+            signed_prekey_id,
+            one_time_prekey_id,
+            kem_ciphertext,
+            replaces,
+        })
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        <Sha256 as Digest>::new()
+            .chain_update(b"fchan session init")
+            .chain_update(self.to_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+fn diffie_hellman(secret: &StaticSecret, public_key: &PublicKey) -> anyhow::Result<Secret> {
+    let shared = secret.diffie_hellman(public_key);
+    if !shared.was_contributory() {
+        bail!("Non-contributory key agreement");
+    }
+    Ok(Secret::from(shared.to_bytes()))
+}
+
+fn kem_encapsulate(public_key: &[u8]) -> anyhow::Result<(Vec<u8>, Secret)> {
+    let Ok(encoded) = Encoded::<KemEncapsulationKey>::try_from(public_key) else {
+        bail!("Invalid KEM key length {}", public_key.len());
+    };
+    let Ok((ciphertext, shared)) =
+        KemEncapsulationKey::from_bytes(&encoded).encapsulate(&mut OsRng)
+    else {
+        bail!("KEM encapsulation failed");
+    };
+
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&shared);
+    Ok((ciphertext.to_vec(), Secret::from(secret)))
+}
+
+fn kem_decapsulate(
+    decapsulation_key: &KemDecapsulationKey,
+    ciphertext: &[u8],
+) -> anyhow::Result<Secret> {
+    let Ok(ciphertext) = Ciphertext::<MlKem768>::try_from(ciphertext) else {
+        bail!("Invalid KEM ciphertext length {}", ciphertext.len());
+    };
+    let Ok(shared) = decapsulation_key.decapsulate(&ciphertext) else {
+        bail!("KEM decapsulation failed");
+    };
+
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&shared);
+    Ok(Secret::from(secret))
+}
+
+/// The KEM secret, if any, is the last agreement, so the session secret is
+/// as strong as the stronger of the two key exchanges.
+fn derive_session_secret(
+    agreements: &[Secret],
+    initiator_identity_key: &PublicKey,
+    responder_identity_key: &PublicKey,
+) -> Secret {
+    let mut ikm = Zeroizing::new(vec![0xff; 32]);
+    for agreement in agreements {
+        ikm.extend(agreement.expose());
+    }
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand_multi_info(
+            &[
+                b"fchan x3dh",
+                initiator_identity_key.as_bytes(),
+                responder_identity_key.as_bytes(),
+            ],
+            &mut secret,
+        )
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    Secret::from(secret)
+}
+
+/// Runs the initiator side of the key agreement against a bundle claimed
+/// from the key registry. Set `require_kem_prekey` if the owner's key record
+/// says they publish one, so that a bundle stripped of it is refused.
+pub fn initiate(
+    identity_secret: &StaticSecret,
+    bundle: &PrekeyBundle,
+    require_kem_prekey: bool,
+) -> anyhow::Result<(SessionInit, Secret)> {
+    if require_kem_prekey && bundle.kem_prekey.is_none() {
+        bail!("Prekey bundle is missing the KEM prekey");
+    }
+
+    bundle
+        .signed_prekey
+        .verify(&bundle.identity_key, &bundle.signing_key)?;
+
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+    let signed_prekey = &bundle.signed_prekey.public_key;
+
+    let mut agreements = vec![
+        diffie_hellman(identity_secret, signed_prekey)?,
+        diffie_hellman(&ephemeral_secret, &bundle.identity_key)?,
+        diffie_hellman(&ephemeral_secret, signed_prekey)?,
+    ];
+    if let Some(one_time_prekey) = &bundle.one_time_prekey {
+        agreements.push(diffie_hellman(
+            &ephemeral_secret,
+            &one_time_prekey.public_key,
+        )?);
+    }
+
+    let kem_ciphertext = match &bundle.kem_prekey {
+        Some(kem_prekey) => {
+            kem_prekey.verify(&bundle.identity_key, &bundle.signing_key)?;
+            let (ciphertext, shared) = kem_encapsulate(&kem_prekey.public_key)?;
+            agreements.push(shared);
+            Some(KemCiphertext {
+                prekey_id: kem_prekey.id,
+                ciphertext,
+            })
+        }
+        None => None,
+    };
+
+    let secret = derive_session_secret(
+        &agreements,
+        &PublicKey::from(identity_secret),
+        &bundle.identity_key,
+    );
+
+    let session_init = SessionInit {
+        ephemeral_key: PublicKey::from(&ephemeral_secret),
+        signed_prekey_id: bundle.signed_prekey.id,
+        one_time_prekey_id: bundle.one_time_prekey.as_ref().map(|p| p.id),
+        kem_ciphertext,
+        replaces: None,
+    };
+
+    Ok((session_init, secret))
+}
+
+/// Private halves of the prekeys published to the key registry. Saved with
+/// the sessions, so that inits sent while we were offline can still be
+/// answered after a restart.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "StoredPrekeys", from = "StoredPrekeys")]
+pub struct PrekeyStore {
+    signed_prekey_id: u32,
+    signed_prekey: StaticSecret,
+    one_time_prekeys: HashMap<u32, StaticSecret>,
+    next_one_time_prekey_id: u32,
+    kem_prekey_id: u32,
+    kem_prekey: KemDecapsulationKey,
+    kem_public_key: Vec<u8>,
+}
+
+impl fmt::Debug for PrekeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrekeyStore")
+            .field("signed_prekey_id", &self.signed_prekey_id)
+            .field("one_time_prekeys", &self.one_time_prekeys.len())
+            .field("kem_prekey_id", &self.kem_prekey_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPrekeys {
+    signed_prekey_id: u32,
+    signed_prekey: Secret,
+    one_time_prekeys: Vec<(u32, Secret)>,
+    next_one_time_prekey_id: u32,
+    kem_prekey_id: u32,
+    kem_prekey: Zeroizing<Vec<u8>>,
+}
+
+impl From<PrekeyStore> for StoredPrekeys {
+    fn from(value: PrekeyStore) -> Self {
+        Self {
+            signed_prekey_id: value.signed_prekey_id,
+            signed_prekey: Secret::from(value.signed_prekey.to_bytes()),
+            one_time_prekeys: value
+                .one_time_prekeys
+                .iter()
+                .map(|(id, secret)| (*id, Secret::from(secret.to_bytes())))
+                .collect(),
+            next_one_time_prekey_id: value.next_one_time_prekey_id,
+            kem_prekey_id: value.kem_prekey_id,
+            kem_prekey: Zeroizing::new(value.kem_prekey.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl From<StoredPrekeys> for PrekeyStore {
+    fn from(value: StoredPrekeys) -> Self {
+        let kem_prekey = match Encoded::<KemDecapsulationKey>::try_from(value.kem_prekey.as_slice())
+        {
+            Ok(encoded) => KemDecapsulationKey::from_bytes(&encoded),
+            // A damaged KEM prekey is replaced; sessions that used it fail to
+            // answer and are initiated again.
+            Err(_) => MlKem768::generate(&mut OsRng).0,
+        };
+
+        Self {
+            signed_prekey_id: value.signed_prekey_id,
+            signed_prekey: StaticSecret::from(*value.signed_prekey.expose()),
+            one_time_prekeys: value
+                .one_time_prekeys
+                .iter()
+                .map(|(id, secret)| (*id, StaticSecret::from(*secret.expose())))
+                .collect(),
+            next_one_time_prekey_id: value.next_one_time_prekey_id,
+            kem_prekey_id: value.kem_prekey_id,
+            kem_public_key: kem_prekey.encapsulation_key().as_bytes().to_vec(),
+            kem_prekey,
+        }
+    }
+}
+
+impl Default for PrekeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrekeyStore {
+    pub fn new() -> Self {
+        let (kem_prekey, kem_public_key) = MlKem768::generate(&mut OsRng);
+        Self {
+            signed_prekey_id: OsRng.next_u32(),
+            signed_prekey: StaticSecret::random_from_rng(OsRng),
+            one_time_prekeys: HashMap::new(),
+            next_one_time_prekey_id: OsRng.next_u32(),
+            kem_prekey_id: OsRng.next_u32(),
+            kem_prekey,
+            kem_public_key: kem_public_key.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn signed_prekey(
+        &self,
+        identity_key: &PublicKey,
+        signing_key: &SigningKey,
+    ) -> SignedPrekey {
+        let public_key = PublicKey::from(&self.signed_prekey);
+        let signature = signing_key.sign(&SignedPrekey::signed_bytes(
+            identity_key,
+            self.signed_prekey_id,
+            &public_key,
+        ));
+
+        SignedPrekey {
+            id: self.signed_prekey_id,
+            public_key,
+            signature,
+        }
+    }
+
+    pub fn kem_prekey(
+        &self,
+        identity_key: &PublicKey,
+        signing_key: &SigningKey,
+    ) -> SignedKemPrekey {
+        let signature = signing_key.sign(&SignedKemPrekey::signed_bytes(
+            identity_key,
+            self.kem_prekey_id,
+            &self.kem_public_key,
+        ));
+
+        SignedKemPrekey {
+            id: self.kem_prekey_id,
+            public_key: self.kem_public_key.clone(),
+            signature,
+        }
+    }
+
+    /// Earlier one-time prekeys are kept, except for the oldest once there
+    /// are more than [`MAX_STORED_ONE_TIME_PREKEYS`].
+    pub fn generate_one_time_prekeys(&mut self, count: usize) -> Vec<OneTimePrekey> {
+        let one_time_prekeys = (0..count)
+            .map(|_| {
+                let id = self.next_one_time_prekey_id;
+                self.next_one_time_prekey_id = self.next_one_time_prekey_id.wrapping_add(1);
+                let secret = StaticSecret::random_from_rng(OsRng);
+                let public_key = PublicKey::from(&secret);
+                self.one_time_prekeys.insert(id, secret);
+                OneTimePrekey { id, public_key }
+            })
+            .collect();
+
+        if self.one_time_prekeys.len() > MAX_STORED_ONE_TIME_PREKEYS {
+            let next_id = self.next_one_time_prekey_id;
+            let mut ids = self.one_time_prekeys.keys().copied().collect::<Vec<_>>();
+            // Ids count up, so the oldest are the furthest behind the next one.
+            ids.sort_by_key(|id| std::cmp::Reverse(next_id.wrapping_sub(*id)));
+            for id in &ids[..ids.len() - MAX_STORED_ONE_TIME_PREKEYS] {
+                self.one_time_prekeys.remove(id);
+            }
+        }
+
+        one_time_prekeys
+    }
+
+    /// Runs the responder side of the key agreement. The one-time prekey used
+    /// by the initiator, if any, is deleted.
+    pub fn respond(
+        &mut self,
+        identity_secret: &StaticSecret,
+        initiator_identity_key: &PublicKey,
+        session_init: &SessionInit,
+    ) -> anyhow::Result<Secret> {
+        if session_init.signed_prekey_id != self.signed_prekey_id {
+            bail!("Unknown signed prekey {}", session_init.signed_prekey_id);
+        }
+
+        let ephemeral_key = &session_init.ephemeral_key;
+
+        let mut agreements = vec![
+            diffie_hellman(&self.signed_prekey, initiator_identity_key)?,
+            diffie_hellman(identity_secret, ephemeral_key)?,
+            diffie_hellman(&self.signed_prekey, ephemeral_key)?,
+        ];
+        if let Some(id) = session_init.one_time_prekey_id {
+            let Some(one_time_prekey) = self.one_time_prekeys.remove(&id) else {
+                bail!("Unknown one-time prekey {id}");
+            };
+            agreements.push(diffie_hellman(&one_time_prekey, ephemeral_key)?);
+        }
+        if let Some(kem_ciphertext) = &session_init.kem_ciphertext {
+            if kem_ciphertext.prekey_id != self.kem_prekey_id {
+                bail!("Unknown KEM prekey {}", kem_ciphertext.prekey_id);
+            }
+            agreements.push(kem_decapsulate(
+                &self.kem_prekey,
+                &kem_ciphertext.ciphertext,
+            )?);
+        }
+
+        Ok(derive_session_secret(
+            &agreements,
+            initiator_identity_key,
+            &PublicKey::from(identity_secret),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initiator_and_responder_agree() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let bob_signing_key = SigningKey::from_bytes(&[7; 32]);
+        let mut bob_prekeys = PrekeyStore::new();
+        let bob_identity_key = PublicKey::from(&bob);
+
+        let bundle = PrekeyBundle {
+            identity_key: bob_identity_key,
+            signing_key: bob_signing_key.verifying_key(),
+            signed_prekey: bob_prekeys.signed_prekey(&bob_identity_key, &bob_signing_key),
+            one_time_prekey: bob_prekeys.generate_one_time_prekeys(1).pop(),
+            kem_prekey: None,
+        };
+
+        let (session_init, alice_secret) = initiate(&alice, &bundle, false).unwrap();
+        let session_init = SessionInit::try_from_bytes(&session_init.to_bytes()).unwrap();
+        let bob_secret = bob_prekeys
+            .respond(&bob, &PublicKey::from(&alice), &session_init)
+            .unwrap();
+
+        assert_eq!(alice_secret.expose(), bob_secret.expose());
+        // The one-time prekey cannot be used twice.
+        assert!(bob_prekeys
+            .respond(&bob, &PublicKey::from(&alice), &session_init)
+            .is_err());
+    }
+
+    #[test]
+    fn forged_signed_prekey_is_rejected() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let bob_signing_key = SigningKey::from_bytes(&[7; 32]);
+        let bob_prekeys = PrekeyStore::new();
+        let bob_identity_key = PublicKey::from(&bob);
+
+        let mut signed_prekey = bob_prekeys.signed_prekey(&bob_identity_key, &bob_signing_key);
+        signed_prekey.public_key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+
+        let bundle = PrekeyBundle {
+            identity_key: bob_identity_key,
+            signing_key: bob_signing_key.verifying_key(),
+            signed_prekey,
+            one_time_prekey: None,
+            kem_prekey: None,
+        };
+
+        assert!(initiate(&alice, &bundle, false).is_err());
+    }
+
+    #[test]
+    fn hybrid_initiator_and_responder_agree() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let bob_signing_key = SigningKey::from_bytes(&[7; 32]);
+        let mut bob_prekeys = PrekeyStore::new();
+        let bob_identity_key = PublicKey::from(&bob);
+
+        let mut bundle = PrekeyBundle {
+            identity_key: bob_identity_key,
+            signing_key: bob_signing_key.verifying_key(),
+            signed_prekey: bob_prekeys.signed_prekey(&bob_identity_key, &bob_signing_key),
+            one_time_prekey: None,
+            kem_prekey: Some(bob_prekeys.kem_prekey(&bob_identity_key, &bob_signing_key)),
+        };
+        let (session_init, alice_secret) = initiate(&alice, &bundle, false).unwrap();
+        let session_init = SessionInit::try_from_bytes(&session_init.to_bytes()).unwrap();
+        assert!(session_init.kem_ciphertext.is_some());
+        let bob_secret = bob_prekeys
+            .respond(&bob, &PublicKey::from(&alice), &session_init)
+            .unwrap();
+        assert_eq!(alice_secret.expose(), bob_secret.expose());
+
+        // The KEM prekey is signed too.
+        bundle.kem_prekey.as_mut().unwrap().public_key[0] ^= 1;
+        assert!(initiate(&alice, &bundle, false).is_err());
+
+        // Stripping it is refused once the owner has advertised it.
+        bundle.kem_prekey = None;
+        assert!(initiate(&alice, &bundle, true).is_err());
+        assert!(initiate(&alice, &bundle, false).is_ok());
+    }
+
+    #[test]
+    fn saved_prekeys_answer_inits() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let bob_signing_key = SigningKey::from_bytes(&[7; 32]);
+        let mut bob_prekeys = PrekeyStore::new();
+        let bob_identity_key = PublicKey::from(&bob);
+
+        let bundle = PrekeyBundle {
+            identity_key: bob_identity_key,
+            signing_key: bob_signing_key.verifying_key(),
+            signed_prekey: bob_prekeys.signed_prekey(&bob_identity_key, &bob_signing_key),
+            one_time_prekey: bob_prekeys.generate_one_time_prekeys(1).pop(),
+            kem_prekey: Some(bob_prekeys.kem_prekey(&bob_identity_key, &bob_signing_key)),
+        };
+        let (mut session_init, alice_secret) = initiate(&alice, &bundle, false).unwrap();
+        session_init.replaces = Some([3; 32]);
+        let session_init = SessionInit::try_from_bytes(&session_init.to_bytes()).unwrap();
+        assert_eq!(session_init.replaces, Some([3; 32]));
+
+        // Bob restarts, and a new pool does not replace the old prekeys.
+        let json = serde_json::to_string(&bob_prekeys).unwrap();
+        let mut bob_prekeys: PrekeyStore = serde_json::from_str(&json).unwrap();
+        bob_prekeys.generate_one_time_prekeys(MAX_STORED_ONE_TIME_PREKEYS - 1);
+        let bob_secret = bob_prekeys
+            .respond(&bob, &PublicKey::from(&alice), &session_init)
+            .unwrap();
+        assert_eq!(alice_secret.expose(), bob_secret.expose());
+
+        bob_prekeys.generate_one_time_prekeys(MAX_STORED_ONE_TIME_PREKEYS + 1);
+        assert_eq!(
+            bob_prekeys.one_time_prekeys.len(),
+            MAX_STORED_ONE_TIME_PREKEYS
+        );
+    }
+}
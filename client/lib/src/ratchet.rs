@@ -0,0 +1,540 @@
+use std::{collections::VecDeque, fmt};
+
+use anyhow::bail;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::{channel::CorrespondentId, secret::Secret};
+
+/// Most message keys that a single header may make us skip over.
+const MAX_SKIP: u64 = 1000;
+/// Most keys kept for messages that have not arrived yet.
+const MAX_SKIPPED_KEYS: usize = 1000;
+/// Most keys kept for our own messages that have not been read back yet.
+const MAX_SENT_KEYS: usize = 1000;
+
+fn hmac(key: &[u8; 32], input: &[u8]) -> [u8; 32] {
+    // unwrap ok because HMAC accepts keys of any length
+    <Hmac<Sha256> as Mac>::new_from_slice(key)
+        .unwrap()
+        .chain_update(input)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// A hash chain over one member's messages in a channel. The key for message
+/// n is derived from the n-th chain key, which is then replaced by the next
+/// one, so a leaked chain key does not expose earlier messages.
+#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SenderRatchet {
+    chain_key: [u8; 32],
+    index: u64,
+}
+
+impl SenderRatchet {
+    pub fn new(seed: &[u8; 32], member: &CorrespondentId) -> Self {
+        let mut chain_key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, seed)
+            .expand_multi_info(&[b"fchan sender ratchet", member.as_ref()], &mut chain_key)
+            .unwrap(); // unwrap ok because 32 bytes is a valid output length
+
+        Self {
+            chain_key,
+            index: 0,
+        }
+    }
+
+    /// Index of the next message this ratchet can produce a key for.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Returns the key for message `index` and the ratchet state after it.
+    /// The state is returned instead of applied so that callers only commit
+    /// it once the message has been processed successfully.
+    pub fn message_key(&self, index: u64) -> anyhow::Result<([u8; 32], Self)> {
+        if index < self.index {
+            bail!(
+                "Key for message {index} has already been deleted (ratchet is at {})",
+                self.index,
+            );
+        }
+
+        let mut chain_key = Zeroizing::new(self.chain_key);
+        for _ in self.index..index {
+            *chain_key = hmac(&chain_key, &[2]);
+        }
+
+        let message_key = hmac(&chain_key, &[1]);
+        let next = Self {
+            chain_key: hmac(&chain_key, &[2]),
+            index: index + 1,
+        };
+
+        Ok((message_key, next))
+    }
+}
+
+/// The sender ratchets of one epoch, for reading each member and for writing.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EpochChains {
+    pub read: Vec<SenderRatchet>,
+    pub write: SenderRatchet,
+}
+
+/// Sender ratchet state of a channel, per epoch, with the read positions that
+/// go with it. It is plain data so that it can be persisted between runs
+/// instead of the secret the ratchets started from.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SenderChains {
+    epochs: Vec<Option<EpochChains>>,
+    pub read_epochs: Option<Vec<usize>>,
+    pub write_epoch: Option<usize>,
+    pub read_cursor: Option<Vec<u64>>,
+    pub write_cursor: Option<Vec<u64>>,
+    #[serde(default)]
+    pub retention_ms: Option<u64>,
+}
+
+impl fmt::Debug for SenderChains {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SenderChains")
+            .field("epochs", &self.epochs.len())
+            .field("read_cursor", &self.read_cursor)
+            .field("write_cursor", &self.write_cursor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SenderChains {
+    pub fn epoch(&self, number: usize) -> Option<&EpochChains> {
+        self.epochs.get(number)?.as_ref()
+    }
+
+    pub fn set_epoch(&mut self, number: usize, chains: EpochChains) {
+        if self.epochs.len() <= number {
+            self.epochs.resize(number + 1, None);
+        }
+        self.epochs[number] = Some(chains);
+    }
+}
+
+/// Sent in the clear in front of every Double Ratchet message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub ratchet_key: PublicKey,
+    pub previous_chain_length: u64,
+    pub message_number: u64,
+}
+
+impl Header {
+    pub const LENGTH: usize = 32 + 8 + 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LENGTH);
+        buf.extend(self.ratchet_key.as_bytes());
+        buf.extend(self.previous_chain_length.to_le_bytes());
+        buf.extend(self.message_number.to_le_bytes());
+        buf
+    }
+
+    /// Splits a message into its header and the remaining ciphertext.
+    pub fn split(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let ratchet_key: [u8; 32] = bytes.get(0..32)?.try_into().ok()?;
+        let previous_chain_length = u64::from_le_bytes(bytes.get(32..40)?.try_into().ok()?);
+        let message_number = u64::from_le_bytes(bytes.get(40..48)?.try_into().ok()?);
+
+        Some((
+            Self {
+                ratchet_key: ratchet_key.into(),
+                previous_chain_length,
+                message_number,
+            },
+            &bytes[Self::LENGTH..],
+        ))
+    }
+}
+
+fn kdf_root(root_key: &[u8; 32], dh_output: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root_key), dh_output)
+        .expand(b"fchan double ratchet", &mut okm)
+        .unwrap(); // unwrap ok because 64 bytes is a valid output length
+
+    // unwrap ok because both halves are 32 bytes
+    (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+}
+
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (hmac(chain_key, &[2]), hmac(chain_key, &[1]))
+}
+
+/// Message keys are bound to the header they were sent with, so that a
+/// modified header fails to decrypt.
+fn bind(message_key: &[u8; 32], header: &Header) -> [u8; 32] {
+    hmac(message_key, &header.to_bytes())
+}
+
+#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
+struct SkippedKey {
+    ratchet_key: [u8; 32],
+    message_number: u64,
+    message_key: [u8; 32],
+    #[serde(default)]
+    stored_at_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
+struct SentKey {
+    message_index: u64,
+    message_key: [u8; 32],
+    stored_at_ms: u64,
+}
+
+/// Double Ratchet state for one side of a direct channel. It is plain data
+/// so that it can be persisted between runs.
+///
+/// The member with the lower correspondent ID initiates, using a ratchet key
+/// derived from the shared secret as the other member's first one, so that no
+/// identity secret ends up in the state. Until the responder has received a
+/// message, it sends on a chain derived from the shared secret alone.
+#[derive(Serialize, Deserialize, Clone, ZeroizeOnDrop)]
+pub struct DoubleRatchet {
+    root_key: [u8; 32],
+    ratchet_secret: [u8; 32],
+    remote_ratchet_key: Option<[u8; 32]>,
+    sending_chain: [u8; 32],
+    receiving_chain: Option<[u8; 32]>,
+    sent: u64,
+    received: u64,
+    previous_chain_length: u64,
+    // Skipped entries wipe themselves when dropped.
+    #[zeroize(skip)]
+    skipped_keys: VecDeque<SkippedKey>,
+    /// Keys for our own messages, so that they can be read back once.
+    #[zeroize(skip)]
+    #[serde(default)]
+    sent_message_keys: VecDeque<SentKey>,
+    /// How long keys for messages that have not been read yet are kept. Once
+    /// they are deleted, those messages cannot be decrypted on this device,
+    /// even if the channel secret leaks later.
+    #[serde(default)]
+    pub retention_ms: Option<u64>,
+    /// Read positions of the channel, so that a channel rebuilt from this
+    /// state continues where the previous one stopped.
+    pub read_cursor: Option<Vec<u64>>,
+    pub write_cursor: Option<Vec<u64>>,
+}
+
+impl fmt::Debug for DoubleRatchet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DoubleRatchet")
+            .field("sent", &self.sent)
+            .field("received", &self.received)
+            .field("read_cursor", &self.read_cursor)
+            .field("write_cursor", &self.write_cursor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DoubleRatchet {
+    pub fn new(
+        shared_secret: &Secret,
+        my_id: &CorrespondentId,
+        correspondent_id: &CorrespondentId,
+    ) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret.expose());
+        let mut bootstrap_chain = [0u8; 32];
+        hkdf.expand(b"fchan double ratchet bootstrap", &mut bootstrap_chain)
+            .unwrap(); // unwrap ok because 32 bytes is a valid output length
+                       // Replaced by a random one as soon as the responder receives a message.
+        let mut responder_ratchet_secret = Zeroizing::new([0u8; 32]);
+        hkdf.expand(
+            b"fchan double ratchet responder key",
+            &mut *responder_ratchet_secret,
+        )
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+
+        let mut state = Self {
+            root_key: *shared_secret.expose(),
+            ratchet_secret: *responder_ratchet_secret,
+            remote_ratchet_key: None,
+            sending_chain: bootstrap_chain,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous_chain_length: 0,
+            skipped_keys: VecDeque::new(),
+            sent_message_keys: VecDeque::new(),
+            retention_ms: None,
+            read_cursor: None,
+            write_cursor: None,
+        };
+
+        if my_id < correspondent_id {
+            let ratchet_secret = StaticSecret::random_from_rng(OsRng);
+            let remote_ratchet_key =
+                PublicKey::from(&StaticSecret::from(*responder_ratchet_secret));
+            let (root_key, sending_chain) = kdf_root(
+                shared_secret.expose(),
+                ratchet_secret
+                    .diffie_hellman(&remote_ratchet_key)
+                    .as_bytes(),
+            );
+            state.root_key = root_key;
+            state.ratchet_secret = ratchet_secret.to_bytes();
+            state.remote_ratchet_key = Some(remote_ratchet_key.to_bytes());
+            state.sending_chain = sending_chain;
+            state.receiving_chain = Some(bootstrap_chain);
+        }
+
+        state
+    }
+
+    /// Returns the header and key for the next outgoing message.
+    pub fn next_sending_key(&mut self) -> (Header, [u8; 32]) {
+        let (chain_key, message_key) = kdf_chain(&self.sending_chain);
+        self.sending_chain = chain_key;
+
+        let header = Header {
+            ratchet_key: PublicKey::from(&StaticSecret::from(self.ratchet_secret)),
+            previous_chain_length: self.previous_chain_length,
+            message_number: self.sent,
+        };
+        self.sent += 1;
+
+        (header, bind(&message_key, &header))
+    }
+
+    /// Returns the key for an incoming message. Callers should work on a
+    /// copy and keep it only if the message decrypts.
+    pub fn receiving_key(&mut self, header: &Header, now_ms: u64) -> anyhow::Result<[u8; 32]> {
+        let ratchet_key = header.ratchet_key.to_bytes();
+
+        if let Some(i) = self
+            .skipped_keys
+            .iter()
+            .position(|k| k.ratchet_key == ratchet_key && k.message_number == header.message_number)
+        {
+            // unwrap ok because the index was just found
+            let skipped = self.skipped_keys.remove(i).unwrap();
+            return Ok(bind(&skipped.message_key, header));
+        }
+
+        if self.remote_ratchet_key != Some(ratchet_key) {
+            self.skip_until(header.previous_chain_length, now_ms)?;
+            self.step(&header.ratchet_key);
+        }
+
+        self.skip_until(header.message_number, now_ms)?;
+        let Some(receiving_chain) = self.receiving_chain else {
+            bail!("No receiving chain");
+        };
+        let (chain_key, message_key) = kdf_chain(&receiving_chain);
+        self.receiving_chain = Some(chain_key);
+        self.received += 1;
+
+        Ok(bind(&message_key, header))
+    }
+
+    fn skip_until(&mut self, message_number: u64, now_ms: u64) -> anyhow::Result<()> {
+        let (Some(remote_ratchet_key), Some(mut chain_key)) =
+            (self.remote_ratchet_key, self.receiving_chain)
+        else {
+            return Ok(());
+        };
+        if message_number > self.received + MAX_SKIP {
+            bail!("Too many skipped messages");
+        }
+
+        while self.received < message_number {
+            let (next, message_key) = kdf_chain(&chain_key);
+            chain_key = next;
+            self.skipped_keys.push_back(SkippedKey {
+                ratchet_key: remote_ratchet_key,
+                message_number: self.received,
+                message_key,
+                stored_at_ms: now_ms,
+            });
+            if self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+                self.skipped_keys.pop_front();
+            }
+            self.received += 1;
+        }
+        self.receiving_chain = Some(chain_key);
+
+        Ok(())
+    }
+
+    fn step(&mut self, remote_ratchet_key: &PublicKey) {
+        self.previous_chain_length = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.remote_ratchet_key = Some(remote_ratchet_key.to_bytes());
+
+        let (root_key, receiving_chain) = kdf_root(
+            &self.root_key,
+            StaticSecret::from(self.ratchet_secret)
+                .diffie_hellman(remote_ratchet_key)
+                .as_bytes(),
+        );
+        let ratchet_secret = StaticSecret::random_from_rng(OsRng);
+        let (root_key, sending_chain) = kdf_root(
+            &root_key,
+            ratchet_secret.diffie_hellman(remote_ratchet_key).as_bytes(),
+        );
+
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.ratchet_secret = ratchet_secret.to_bytes();
+        self.sending_chain = sending_chain;
+    }
+
+    pub fn remember_sent_key(&mut self, message_index: u64, key: [u8; 32], now_ms: u64) {
+        self.sent_message_keys.push_back(SentKey {
+            message_index,
+            message_key: key,
+            stored_at_ms: now_ms,
+        });
+        if self.sent_message_keys.len() > MAX_SENT_KEYS {
+            self.sent_message_keys.pop_front();
+        }
+    }
+
+    pub fn sent_key(&self, message_index: u64) -> Option<[u8; 32]> {
+        self.sent_message_keys
+            .iter()
+            .find(|k| k.message_index == message_index)
+            .map(|k| k.message_key)
+    }
+
+    pub fn forget_sent_key(&mut self, message_index: u64) {
+        self.sent_message_keys
+            .retain(|k| k.message_index != message_index);
+    }
+
+    /// Deletes the keys that have been kept for longer than the retention
+    /// period.
+    pub fn shred(&mut self, now_ms: u64) {
+        let Some(retention_ms) = self.retention_ms else {
+            return;
+        };
+        let cutoff = now_ms.saturating_sub(retention_ms);
+        self.skipped_keys.retain(|k| k.stored_at_ms >= cutoff);
+        self.sent_message_keys.retain(|k| k.stored_at_ms >= cutoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_deleted_after_use() {
+        let member: CorrespondentId = [1; 32].into();
+        let ratchet = SenderRatchet::new(&[9; 32], &member);
+
+        let (k0, after_0) = ratchet.message_key(0).unwrap();
+        let (k2, after_2) = after_0.message_key(2).unwrap();
+        let (k2_direct, _) = ratchet.message_key(2).unwrap();
+
+        assert_ne!(k0, k2);
+        assert_eq!(k2, k2_direct);
+        assert_eq!(after_2.index(), 3);
+        assert!(after_2.message_key(2).is_err());
+    }
+
+    fn pair() -> (DoubleRatchet, DoubleRatchet) {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let alice_id: CorrespondentId = PublicKey::from(&alice).to_bytes().into();
+        let bob_id: CorrespondentId = PublicKey::from(&bob).to_bytes().into();
+        (
+            DoubleRatchet::new(&[5; 32].into(), &alice_id, &bob_id),
+            DoubleRatchet::new(&[5; 32].into(), &bob_id, &alice_id),
+        )
+    }
+
+    #[test]
+    fn double_ratchet_state_holds_no_identity_secret() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let alice_id: CorrespondentId = PublicKey::from(&alice).to_bytes().into();
+        for bob_id in [[0; 32].into(), [0xff; 32].into()] {
+            let state = DoubleRatchet::new(&[5; 32].into(), &alice_id, &bob_id);
+            assert_ne!(state.ratchet_secret, alice.to_bytes());
+            let stored = serde_json::to_string(&state).unwrap();
+            assert!(!stored.contains(&serde_json::to_string(&alice.to_bytes()).unwrap()));
+        }
+    }
+
+    #[test]
+    fn double_ratchet_either_side_may_send_first() {
+        for responder_first in [false, true] {
+            let (a, b) = pair();
+            let (mut initiator, mut responder) = if a.receiving_chain.is_some() {
+                (a, b)
+            } else {
+                (b, a)
+            };
+
+            if responder_first {
+                let (h, k) = responder.next_sending_key();
+                assert_eq!(initiator.receiving_key(&h, 0).unwrap(), k);
+            }
+            let (h, k) = initiator.next_sending_key();
+            assert_eq!(responder.receiving_key(&h, 0).unwrap(), k);
+            let (h, k) = responder.next_sending_key();
+            assert_eq!(initiator.receiving_key(&h, 0).unwrap(), k);
+            let (h, k) = initiator.next_sending_key();
+            assert_eq!(responder.receiving_key(&h, 0).unwrap(), k);
+        }
+    }
+
+    #[test]
+    fn double_ratchet_handles_out_of_order_messages() {
+        let (mut alice, mut bob) = pair();
+
+        let (h0, k0) = alice.next_sending_key();
+        let (h1, k1) = alice.next_sending_key();
+        let (h2, k2) = bob.next_sending_key();
+
+        assert_eq!(bob.receiving_key(&h1, 0).unwrap(), k1);
+        assert_eq!(alice.receiving_key(&h2, 0).unwrap(), k2);
+        assert_eq!(bob.receiving_key(&h0, 0).unwrap(), k0);
+
+        // Keys are only available once.
+        assert_ne!(bob.clone().receiving_key(&h0, 0).ok(), Some(k0));
+
+        // State survives a round trip through storage.
+        let mut bob: DoubleRatchet =
+            serde_json::from_str(&serde_json::to_string(&bob).unwrap()).unwrap();
+        let (h3, k3) = alice.next_sending_key();
+        assert_eq!(bob.receiving_key(&h3, 0).unwrap(), k3);
+    }
+
+    #[test]
+    fn shred_deletes_keys_past_retention() {
+        let (mut alice, mut bob) = pair();
+        bob.retention_ms = Some(1000);
+
+        let (h0, k0) = alice.next_sending_key();
+        let (h1, k1) = alice.next_sending_key();
+        assert_eq!(bob.receiving_key(&h1, 5000).unwrap(), k1);
+        let (_, sent) = bob.next_sending_key();
+        bob.remember_sent_key(0, sent, 5000);
+
+        bob.shred(6000);
+        assert_eq!(bob.clone().receiving_key(&h0, 6000).unwrap(), k0);
+        assert_eq!(bob.sent_key(0), Some(sent));
+
+        bob.shred(6001);
+        assert_ne!(bob.receiving_key(&h0, 6001).ok(), Some(k0));
+        assert_eq!(bob.sent_key(0), None);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// 32 bytes of key material. Wiped from memory when dropped and never
+/// printed by `Debug`.
+#[derive(Serialize, Deserialize, Clone, Zeroize, ZeroizeOnDrop)]
+#[serde(transparent)]
+pub struct Secret([u8; 32]);
+
+impl Secret {
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// The raw key material. Named so that every use is easy to find.
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Secret {
+    fn from(mut value: [u8; 32]) -> Self {
+        let secret = Self(value);
+        value.zeroize();
+        secret
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let secret = Secret::from([0xab; 32]);
+        let printed = format!("{secret:?}");
+        assert_eq!(printed, "Secret([REDACTED])");
+        assert!(!printed.contains("171"));
+    }
+
+    #[test]
+    fn serializes_like_a_byte_array() {
+        let bytes = [7u8; 32];
+        let json = serde_json::to_string(&Secret::from(bytes)).unwrap();
+        assert_eq!(json, serde_json::to_string(&bytes).unwrap());
+        let secret: Secret = serde_json::from_str(&json).unwrap();
+        assert_eq!(secret.expose(), &bytes);
+    }
+}
@@ -0,0 +1,1119 @@
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::{
+    channel::{ChannelVersion, SequenceHash},
+    secret::Secret,
+};
+
+/// The version of every tree group channel. Welcomes do not carry one, so a
+/// newer version needs a new welcome format.
+pub const CHANNEL_VERSION: ChannelVersion = ChannelVersion::V4;
+
+const COMMIT_TAG_LENGTH: usize = 16;
+
+// Nodes are numbered in array order, as in MLS: leaves at even indices and
+// parents in between, in a full tree whose leaf count is a power of two.
+
+fn level(x: u32) -> u32 {
+    x.trailing_ones()
+}
+
+fn left(x: u32) -> u32 {
+    x ^ (1 << (level(x) - 1))
+}
+
+fn right(x: u32) -> u32 {
+    x ^ (3 << (level(x) - 1))
+}
+
+fn parent(x: u32) -> u32 {
+    let k = level(x);
+    let b = (x >> (k + 1)) & 1;
+    (x | (1 << k)) ^ (b << (k + 1))
+}
+
+fn sibling(x: u32) -> u32 {
+    let p = parent(x);
+    if x < p {
+        right(p)
+    } else {
+        left(p)
+    }
+}
+
+fn is_below(leaf: u32, x: u32) -> bool {
+    let span = (1 << level(x)) - 1;
+    leaf + span >= x && leaf <= x + span
+}
+
+fn leaf_node(leaf: u32) -> u32 {
+    leaf * 2
+}
+
+fn derive(secret: &Secret, label: &[u8]) -> Secret {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret.expose())
+        .expand_multi_info(&[b"fchan tree ", label], &mut okm)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    Secret::from(okm)
+}
+
+fn node_key(path_secret: &Secret) -> StaticSecret {
+    StaticSecret::from(*derive(path_secret, b"node").expose())
+}
+
+fn path_cipher(shared: &[u8; 32], ephemeral_key: &PublicKey) -> XChaCha20Poly1305 {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(b"fchan tree path"), shared)
+        .expand(ephemeral_key.as_bytes(), &mut *key)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+    XChaCha20Poly1305::new((&*key).into())
+}
+
+/// Encrypts a path secret to a node's key. Every ciphertext has its own
+/// ephemeral key, so a fixed nonce is never reused.
+fn seal_path_secret(
+    path_secret: &Secret,
+    recipient: &PublicKey,
+    node: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret);
+    let shared = Zeroizing::new(ephemeral_secret.diffie_hellman(recipient).to_bytes());
+
+    let mut buf = ephemeral_key.as_bytes().to_vec();
+    let ciphertext = path_cipher(&shared, &ephemeral_key).encrypt(
+        &XNonce::default(),
+        Payload {
+            msg: path_secret.expose(),
+            aad: &node.to_le_bytes(),
+        },
+    );
+    match ciphertext {
+        Ok(c) => buf.extend(c),
+        Err(e) => bail!(e),
+    }
+    Ok(buf)
+}
+
+fn open_path_secret(secret_key: &StaticSecret, sealed: &[u8], node: u32) -> anyhow::Result<Secret> {
+    let Some((ephemeral_key, ciphertext)) = sealed.split_at_checked(32) else {
+        bail!("Path secret ciphertext too short");
+    };
+    // unwrap ok because the length was checked above
+    let ephemeral_key = PublicKey::from(<[u8; 32]>::try_from(ephemeral_key).unwrap());
+    let shared = Zeroizing::new(secret_key.diffie_hellman(&ephemeral_key).to_bytes());
+
+    let path_secret = match path_cipher(&shared, &ephemeral_key).decrypt(
+        &XNonce::default(),
+        Payload {
+            msg: ciphertext,
+            aad: &node.to_le_bytes(),
+        },
+    ) {
+        Ok(s) => Zeroizing::new(s),
+        Err(e) => bail!(e),
+    };
+    match <[u8; 32]>::try_from(path_secret.as_slice()) {
+        Ok(path_secret) => Ok(Secret::from(path_secret)),
+        Err(_) => bail!("Path secret has the wrong length"),
+    }
+}
+
+/// Key shared by the committer and `member` for the tag that proves to
+/// `member` who made a commit.
+fn commit_mac(identity: &StaticSecret, member: &[u8; 32]) -> Hmac<Sha256> {
+    let shared = Zeroizing::new(
+        identity
+            .diffie_hellman(&PublicKey::from(*member))
+            .to_bytes(),
+    );
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, &*shared)
+        .expand(b"fchan tree commit authentication", &mut *key)
+        .unwrap(); // unwrap ok because 32 bytes is a valid output length
+                   // unwrap ok because HMAC accepts keys of any length
+    <Hmac<Sha256> as Mac>::new_from_slice(&*key).unwrap()
+}
+
+/// The public half of a ratchet tree, which every member holds identically.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PublicTree {
+    /// Public key of each node, or `None` if it is blank.
+    nodes: Vec<Option<[u8; 32]>>,
+    /// Identity key of the member at each leaf.
+    identities: Vec<Option<[u8; 32]>>,
+}
+
+impl PublicTree {
+    fn root(&self) -> u32 {
+        self.identities.len() as u32 - 1
+    }
+
+    fn direct_path(&self, x: u32) -> Vec<u32> {
+        let root = self.root();
+        let mut path = vec![];
+        let mut x = x;
+        while x != root {
+            x = parent(x);
+            path.push(x);
+        }
+        path
+    }
+
+    /// The non-blank nodes that together cover every leaf below `x`.
+    fn resolution(&self, x: u32) -> Vec<u32> {
+        if self.nodes[x as usize].is_some() {
+            vec![x]
+        } else if level(x) == 0 {
+            vec![]
+        } else {
+            let mut resolution = self.resolution(left(x));
+            resolution.extend(self.resolution(right(x)));
+            resolution
+        }
+    }
+
+    fn leaf_of(&self, identity: &PublicKey) -> Option<u32> {
+        self.identities
+            .iter()
+            .position(|i| i.as_ref() == Some(identity.as_bytes()))
+            .map(|i| i as u32)
+    }
+
+    pub fn members(&self) -> Vec<PublicKey> {
+        self.identities
+            .iter()
+            .flatten()
+            .map(|i| (*i).into())
+            .collect()
+    }
+
+    /// Everyone a commit by `committer` is tagged for: the other members in
+    /// leaf order, then the added ones.
+    fn commit_recipients(&self, committer: u32, added: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        self.identities
+            .iter()
+            .enumerate()
+            .filter(|(leaf, _)| *leaf as u32 != committer)
+            .filter_map(|(_, identity)| *identity)
+            .chain(added.iter().copied())
+            .collect()
+    }
+
+    fn blank_path(&mut self, leaf: u32) {
+        for node in self.direct_path(leaf_node(leaf)) {
+            self.nodes[node as usize] = None;
+        }
+    }
+
+    /// Removes and adds members the same way on every side of a commit.
+    /// Added members take the first free leaf, and the tree doubles when
+    /// there is none.
+    fn apply_changes(&mut self, removed: &[u32], added: &[[u8; 32]]) -> anyhow::Result<()> {
+        for &leaf in removed {
+            if self
+                .identities
+                .get(leaf as usize)
+                .copied()
+                .flatten()
+                .is_none()
+            {
+                bail!("Cannot remove empty leaf {leaf}");
+            }
+            self.identities[leaf as usize] = None;
+            self.nodes[leaf_node(leaf) as usize] = None;
+            self.blank_path(leaf);
+        }
+
+        for identity in added {
+            if self.identities.contains(&Some(*identity)) {
+                bail!("Already a member of the group");
+            }
+            let leaf = match self.identities.iter().position(Option::is_none) {
+                Some(leaf) => leaf as u32,
+                None => {
+                    let leaf_count = self.identities.len();
+                    self.identities.resize(leaf_count * 2, None);
+                    self.nodes.resize(leaf_count * 4 - 1, None);
+                    leaf_count as u32
+                }
+            };
+            self.identities[leaf as usize] = Some(*identity);
+            // Until the new member commits, its identity key is its leaf key.
+            self.nodes[leaf_node(leaf) as usize] = Some(*identity);
+            self.blank_path(leaf);
+        }
+
+        Ok(())
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hash = <Sha256 as Digest>::new().chain_update(b"fchan tree");
+        for key in self.nodes.iter().chain(&self.identities) {
+            hash.update(key.unwrap_or([0; 32]));
+        }
+        hash.finalize().into()
+    }
+}
+
+/// Starts a new epoch of a tree group. The committer gives its leaf and every
+/// node above it new keys, and encrypts the secrets of those nodes to the
+/// other side of the tree, so that every remaining member learns the new
+/// epoch secret from a number of ciphertexts that is logarithmic in the size
+/// of the group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    pub committer: u32,
+    pub removed: Vec<u32>,
+    pub added: Vec<[u8; 32]>,
+    pub leaf_key: [u8; 32],
+    /// New keys of the nodes on the committer's direct path, bottom up.
+    pub path_keys: Vec<[u8; 32]>,
+    /// For each node on the direct path, its path secret encrypted to each
+    /// node in the resolution of the sibling below it.
+    pub path_secrets: Vec<Vec<Vec<u8>>>,
+    /// One tag per recipient, keyed with the key that the committer's
+    /// identity shares with theirs, over the tree before the commit and the
+    /// rest of the commit. Only the member at `committer` can make them.
+    pub tags: Vec<[u8; COMMIT_TAG_LENGTH]>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, tail) = self.0.split_at_checked(n)?;
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn key(&mut self) -> Option<[u8; 32]> {
+        self.bytes(32)?.try_into().ok()
+    }
+
+    fn keys(&mut self) -> Option<Vec<[u8; 32]>> {
+        (0..self.u32()?).map(|_| self.key()).collect()
+    }
+
+    fn optional_keys(&mut self) -> Option<Vec<Option<[u8; 32]>>> {
+        (0..self.u32()?)
+            .map(|_| match self.bytes(1)?[0] {
+                0 => Some(None),
+                _ => self.key().map(Some),
+            })
+            .collect()
+    }
+
+    fn finish(self) -> Option<()> {
+        self.0.is_empty().then_some(())
+    }
+}
+
+fn put_keys(buf: &mut Vec<u8>, keys: &[[u8; 32]]) {
+    buf.extend((keys.len() as u32).to_le_bytes());
+    for key in keys {
+        buf.extend(key);
+    }
+}
+
+fn put_optional_keys(buf: &mut Vec<u8>, keys: &[Option<[u8; 32]>]) {
+    buf.extend((keys.len() as u32).to_le_bytes());
+    for key in keys {
+        match key {
+            Some(key) => {
+                buf.push(1);
+                buf.extend(key);
+            }
+            None => buf.push(0),
+        }
+    }
+}
+
+impl Commit {
+    fn write_content(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.committer.to_le_bytes());
+        buf.extend((self.removed.len() as u32).to_le_bytes());
+        for leaf in &self.removed {
+            buf.extend(leaf.to_le_bytes());
+        }
+        put_keys(buf, &self.added);
+        buf.extend(self.leaf_key);
+        put_keys(buf, &self.path_keys);
+        for ciphertexts in &self.path_secrets {
+            buf.extend((ciphertexts.len() as u32).to_le_bytes());
+            for ciphertext in ciphertexts {
+                buf.extend((ciphertext.len() as u32).to_le_bytes());
+                buf.extend(ciphertext);
+            }
+        }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        self.write_content(buf);
+        buf.extend((self.tags.len() as u32).to_le_bytes());
+        for tag in &self.tags {
+            buf.extend(tag);
+        }
+    }
+
+    fn tag_mac(
+        &self,
+        identity: &StaticSecret,
+        member: &[u8; 32],
+        tree: &PublicTree,
+    ) -> Hmac<Sha256> {
+        let mut content = vec![];
+        self.write_content(&mut content);
+        commit_mac(identity, member)
+            .chain_update(tree.hash())
+            .chain_update(content)
+    }
+
+    /// Checks the tag for `identity`, in `tree` as it was before the commit.
+    fn verify(&self, identity: &StaticSecret, tree: &PublicTree) -> anyhow::Result<()> {
+        let Some(committer) = tree
+            .identities
+            .get(self.committer as usize)
+            .copied()
+            .flatten()
+        else {
+            bail!("Commit from an empty leaf");
+        };
+        let own_identity = PublicKey::from(identity).to_bytes();
+        if committer == own_identity {
+            bail!("Own commits are applied when they are made");
+        }
+        let recipients = tree.commit_recipients(self.committer, &self.added);
+        if self.tags.len() != recipients.len() {
+            bail!("Commit has the wrong number of tags");
+        }
+        let Some(i) = recipients.iter().position(|r| *r == own_identity) else {
+            bail!("Commit is not addressed to us");
+        };
+        match self
+            .tag_mac(identity, &committer, tree)
+            .verify_truncated_left(&self.tags[i])
+        {
+            Ok(()) => Ok(()),
+            Err(_) => bail!("Commit is not from the member at leaf {}", self.committer),
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Option<Self> {
+        let committer = reader.u32()?;
+        let removed = (0..reader.u32()?)
+            .map(|_| reader.u32())
+            .collect::<Option<_>>()?;
+        let added = reader.keys()?;
+        let leaf_key = reader.key()?;
+        let path_keys = reader.keys()?;
+        let path_secrets = (0..path_keys.len())
+            .map(|_| {
+                (0..reader.u32()?)
+                    .map(|_| {
+                        let len = reader.u32()? as usize;
+                        reader.bytes(len).map(<[u8]>::to_vec)
+                    })
+                    .collect()
+            })
+            .collect::<Option<_>>()?;
+        let tags = (0..reader.u32()?)
+            .map(|_| reader.bytes(COMMIT_TAG_LENGTH)?.try_into().ok())
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            committer,
+            removed,
+            added,
+            leaf_key,
+            path_keys,
+            path_secrets,
+            tags,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.write(&mut buf);
+        buf
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let commit = Self::read(&mut reader)?;
+        reader.finish()?;
+        Some(commit)
+    }
+}
+
+/// A ratchet tree along with the secrets we know in it: those of the nodes
+/// on our direct path. Our leaf's secret is our identity key until our first
+/// commit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RatchetTree {
+    public: PublicTree,
+    secrets: BTreeMap<u32, Secret>,
+}
+
+impl RatchetTree {
+    /// A tree with a leaf for each of `members`, keyed with their identity
+    /// keys, and no parent keys yet.
+    pub fn new(members: &[PublicKey]) -> Self {
+        let mut identities = members.iter().map(|m| m.to_bytes()).collect::<Vec<_>>();
+        identities.sort();
+        identities.dedup();
+        let leaf_count = identities.len().max(1).next_power_of_two();
+
+        let mut nodes = vec![None; leaf_count * 2 - 1];
+        for (leaf, identity) in identities.iter().enumerate() {
+            nodes[leaf_node(leaf as u32) as usize] = Some(*identity);
+        }
+        let mut identities = identities.into_iter().map(Some).collect::<Vec<_>>();
+        identities.resize(leaf_count, None);
+
+        Self {
+            public: PublicTree { nodes, identities },
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_public(public: PublicTree) -> Self {
+        Self {
+            public,
+            secrets: BTreeMap::new(),
+        }
+    }
+
+    pub fn public(&self) -> &PublicTree {
+        &self.public
+    }
+
+    pub fn members(&self) -> Vec<PublicKey> {
+        self.public.members()
+    }
+
+    fn own_leaf(&self, identity: &StaticSecret) -> anyhow::Result<u32> {
+        match self.public.leaf_of(&PublicKey::from(identity)) {
+            Some(leaf) => Ok(leaf),
+            None => bail!("Not a member of the tree"),
+        }
+    }
+
+    fn secret_key(&self, identity: &StaticSecret, node: u32) -> Option<StaticSecret> {
+        match self.secrets.get(&node) {
+            Some(secret) => Some(StaticSecret::from(*secret.expose())),
+            None if Some(node) == self.own_leaf(identity).ok().map(leaf_node) => {
+                Some(identity.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Forgets the secrets of nodes that are no longer on our direct path.
+    fn prune_secrets(&mut self, own_leaf: u32) {
+        let mut path = self.public.direct_path(leaf_node(own_leaf));
+        path.push(leaf_node(own_leaf));
+        self.secrets.retain(|node, _| path.contains(node));
+    }
+
+    /// Removes `removed` and adds `added`, then gives our leaf and direct
+    /// path new keys. Returns the commit for the other members and the
+    /// commit secret of the new epoch.
+    pub fn commit(
+        &mut self,
+        identity: &StaticSecret,
+        removed: &[PublicKey],
+        added: &[PublicKey],
+    ) -> anyhow::Result<(Commit, Secret)> {
+        let committer = self.own_leaf(identity)?;
+        let mut removed_leaves = vec![];
+        for member in removed {
+            match self.public.leaf_of(member) {
+                Some(leaf) if leaf == committer => bail!("Cannot remove ourselves"),
+                Some(leaf) => removed_leaves.push(leaf),
+                None => bail!("Not a member of the tree"),
+            }
+        }
+        let added = added.iter().map(|a| a.to_bytes()).collect::<Vec<_>>();
+        let before = self.public.clone();
+        self.public.apply_changes(&removed_leaves, &added)?;
+
+        let leaf_secret = Secret::random();
+        let leaf_key = PublicKey::from(&StaticSecret::from(*leaf_secret.expose())).to_bytes();
+        self.public.nodes[leaf_node(committer) as usize] = Some(leaf_key);
+        self.secrets.insert(leaf_node(committer), leaf_secret);
+
+        let path = self.public.direct_path(leaf_node(committer));
+        let mut path_secret = Secret::random();
+        let mut path_keys = vec![];
+        let mut path_secrets = vec![];
+        let mut child = leaf_node(committer);
+        for &node in &path {
+            let node_secret = node_key(&path_secret);
+            let node_public_key = PublicKey::from(&node_secret).to_bytes();
+            self.public.nodes[node as usize] = Some(node_public_key);
+            self.secrets
+                .insert(node, Secret::from(node_secret.to_bytes()));
+            path_keys.push(node_public_key);
+
+            let mut ciphertexts = vec![];
+            for recipient in self.public.resolution(sibling(child)) {
+                // unwrap ok because the resolution only contains non-blank nodes
+                let recipient_key = PublicKey::from(self.public.nodes[recipient as usize].unwrap());
+                ciphertexts.push(seal_path_secret(&path_secret, &recipient_key, node)?);
+            }
+            path_secrets.push(ciphertexts);
+
+            path_secret = derive(&path_secret, b"path");
+            child = node;
+        }
+        self.prune_secrets(committer);
+
+        let mut commit = Commit {
+            committer,
+            removed: removed_leaves,
+            added,
+            leaf_key,
+            path_keys,
+            path_secrets,
+            tags: vec![],
+        };
+        commit.tags = before
+            .commit_recipients(committer, &commit.added)
+            .iter()
+            .map(|recipient| {
+                let tag = commit
+                    .tag_mac(identity, recipient, &before)
+                    .finalize()
+                    .into_bytes();
+                // unwrap ok because HMAC-SHA256 tags are longer
+                tag[..COMMIT_TAG_LENGTH].try_into().unwrap()
+            })
+            .collect();
+        Ok((commit, derive(&path_secret, b"commit")))
+    }
+
+    /// Applies another member's commit. Returns the commit secret of the new
+    /// epoch, or `None` if the commit removed us. Commits whose tag for us
+    /// does not match the member at their committer leaf are rejected.
+    pub fn process(
+        &mut self,
+        identity: &StaticSecret,
+        commit: &Commit,
+    ) -> anyhow::Result<Option<Secret>> {
+        commit.verify(identity, &self.public)?;
+        if self
+            .public
+            .leaf_of(&PublicKey::from(identity))
+            .is_some_and(|leaf| commit.removed.contains(&leaf))
+        {
+            return Ok(None);
+        }
+        self.public.apply_changes(&commit.removed, &commit.added)?;
+        let own_leaf = self.own_leaf(identity)?;
+        if self
+            .public
+            .identities
+            .get(commit.committer as usize)
+            .copied()
+            .flatten()
+            .is_none()
+        {
+            bail!("Commit removes its committer");
+        }
+
+        let committer = leaf_node(commit.committer);
+        let path = self.public.direct_path(committer);
+        if commit.path_keys.len() != path.len() || commit.path_secrets.len() != path.len() {
+            bail!("Commit path does not match the tree");
+        }
+
+        // The first node on the committer's path that is above us is the
+        // lowest one whose secret was encrypted to a node we hold.
+        let mut child = committer;
+        let mut found = None;
+        for (i, &node) in path.iter().enumerate() {
+            let copath = sibling(child);
+            if is_below(leaf_node(own_leaf), copath) {
+                found = Some((i, copath));
+                break;
+            }
+            child = node;
+        }
+        let Some((first, copath)) = found else {
+            bail!("Commit path does not cover us");
+        };
+
+        let resolution = self.public.resolution(copath);
+        if resolution.len() != commit.path_secrets[first].len() {
+            bail!("Commit has the wrong number of path secrets");
+        }
+        let Some((recipient, secret_key)) = resolution
+            .iter()
+            .enumerate()
+            .find_map(|(i, &node)| Some((i, self.secret_key(identity, node)?)))
+        else {
+            bail!("No key to decrypt the commit with");
+        };
+        let mut path_secret = open_path_secret(
+            &secret_key,
+            &commit.path_secrets[first][recipient],
+            path[first],
+        )?;
+
+        self.public.nodes[committer as usize] = Some(commit.leaf_key);
+        for (i, &node) in path.iter().enumerate() {
+            self.public.nodes[node as usize] = Some(commit.path_keys[i]);
+        }
+        for (&node, path_key) in path.iter().zip(&commit.path_keys).skip(first) {
+            let node_secret = node_key(&path_secret);
+            if PublicKey::from(&node_secret).as_bytes() != path_key {
+                bail!("Path secret does not match the committed key");
+            }
+            self.secrets
+                .insert(node, Secret::from(node_secret.to_bytes()));
+            path_secret = derive(&path_secret, b"path");
+        }
+        self.prune_secrets(own_leaf);
+
+        Ok(Some(derive(&path_secret, b"commit")))
+    }
+}
+
+/// Sent to each new member through a direct channel: the tree as it was
+/// before the commit that added them, so that they can process it like
+/// everyone else.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Welcome {
+    pub group_id: [u8; 32],
+    pub name: String,
+    /// The epoch that the commit starts.
+    pub epoch: u64,
+    pub tree: PublicTree,
+    pub commit: Commit,
+}
+
+impl Welcome {
+    /// The identity key of the member who made the commit.
+    pub fn committer(&self) -> Option<PublicKey> {
+        self.tree
+            .identities
+            .get(self.commit.committer as usize)
+            .copied()
+            .flatten()
+            .map(PublicKey::from)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend(self.group_id);
+        buf.extend(self.epoch.to_le_bytes());
+        put_optional_keys(&mut buf, &self.tree.nodes);
+        put_optional_keys(&mut buf, &self.tree.identities);
+        self.commit.write(&mut buf);
+        buf.extend(self.name.as_bytes());
+        buf
+    }
+
+    pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let group_id = reader.key()?;
+        let epoch = reader.u64()?;
+        let nodes = reader.optional_keys()?;
+        let identities = reader.optional_keys()?;
+        if !identities.len().is_power_of_two() || nodes.len() != identities.len() * 2 - 1 {
+            return None;
+        }
+        let commit = Commit::read(&mut reader)?;
+        let name = String::from_utf8(reader.0.to_vec()).ok()?;
+
+        Some(Self {
+            group_id,
+            name,
+            epoch,
+            tree: PublicTree { nodes, identities },
+            commit,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TreeEpoch {
+    number: u64,
+    secret: Secret,
+    members: Vec<[u8; 32]>,
+}
+
+/// A group whose epoch secrets come from a ratchet tree, so that changing
+/// its membership costs one transaction instead of one per member. Commits
+/// are posted to the group's control channel: a write-once slot per epoch,
+/// encrypted to that epoch, followed by another slot for each entry that
+/// turned out to be invalid. Contains secrets, so store it
+/// accordingly.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TreeGroup {
+    pub group_id: [u8; 32],
+    pub name: String,
+    tree: RatchetTree,
+    /// Every epoch we have been a member in, oldest first.
+    epochs: Vec<TreeEpoch>,
+    removed: bool,
+    /// Number of invalid entries skipped in the current epoch.
+    #[serde(default)]
+    control_attempt: u32,
+}
+
+impl TreeGroup {
+    fn epoch_secret(
+        group_id: &[u8; 32],
+        number: u64,
+        tree: &PublicTree,
+        commit_secret: &Secret,
+    ) -> Secret {
+        let mut okm = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(group_id), commit_secret.expose())
+            .expand_multi_info(
+                &[b"fchan tree epoch", &number.to_le_bytes(), &tree.hash()],
+                &mut okm,
+            )
+            .unwrap(); // unwrap ok because 32 bytes is a valid output length
+        Secret::from(okm)
+    }
+
+    fn push_epoch(&mut self, number: u64, commit_secret: &Secret) {
+        let secret = Self::epoch_secret(&self.group_id, number, self.tree.public(), commit_secret);
+        self.epochs.push(TreeEpoch {
+            number,
+            secret,
+            members: self.tree.members().iter().map(|m| m.to_bytes()).collect(),
+        });
+        self.control_attempt = 0;
+    }
+
+    /// Creates a group of `members` and us. Returns the group and the welcome
+    /// for the other members.
+    pub fn create(
+        identity: &StaticSecret,
+        name: &str,
+        members: &[PublicKey],
+    ) -> anyhow::Result<(Self, Welcome)> {
+        let mut members = members.to_vec();
+        members.push(PublicKey::from(identity));
+        let mut tree = RatchetTree::new(&members);
+        let before = tree.public().clone();
+        let (commit, commit_secret) = tree.commit(identity, &[], &[])?;
+
+        let mut group_id = [0u8; 32];
+        OsRng.fill_bytes(&mut group_id);
+        let mut group = Self {
+            group_id,
+            name: name.to_string(),
+            tree,
+            epochs: vec![],
+            removed: false,
+            control_attempt: 0,
+        };
+        group.push_epoch(0, &commit_secret);
+
+        let welcome = Welcome {
+            group_id,
+            name: name.to_string(),
+            epoch: 0,
+            tree: before,
+            commit,
+        };
+        Ok((group, welcome))
+    }
+
+    pub fn join(identity: &StaticSecret, welcome: &Welcome) -> anyhow::Result<Self> {
+        let mut tree = RatchetTree::from_public(welcome.tree.clone());
+        let Some(commit_secret) = tree.process(identity, &welcome.commit)? else {
+            bail!("Welcome removes us from the group");
+        };
+        let mut group = Self {
+            group_id: welcome.group_id,
+            name: welcome.name.clone(),
+            tree,
+            epochs: vec![],
+            removed: false,
+            control_attempt: 0,
+        };
+        group.push_epoch(welcome.epoch, &commit_secret);
+        Ok(group)
+    }
+
+    pub fn epoch(&self) -> u64 {
+        // unwrap ok because a group always has the epoch it was created or
+        // joined in
+        self.epochs.last().unwrap().number
+    }
+
+    /// Whether a commit removed us. The group cannot be followed further.
+    pub fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    pub fn members(&self) -> Vec<PublicKey> {
+        self.tree.members()
+    }
+
+    /// The secret and members of every epoch we have been in, oldest first.
+    pub fn epochs(&self) -> impl Iterator<Item = (u64, &Secret, Vec<PublicKey>)> {
+        self.epochs.iter().map(|e| {
+            (
+                e.number,
+                &e.secret,
+                e.members.iter().map(|m| (*m).into()).collect(),
+            )
+        })
+    }
+
+    /// Channels of different epochs never share slots.
+    pub fn context(&self, epoch: u64) -> Vec<u8> {
+        [self.group_id.as_slice(), b"tree", &epoch.to_le_bytes()].concat()
+    }
+
+    fn current_secret(&self) -> &Secret {
+        // unwrap ok because a group always has at least one epoch
+        &self.epochs.last().unwrap().secret
+    }
+
+    fn control_mac(&self, label: &[u8]) -> Hmac<Sha256> {
+        // unwrap ok because HMAC accepts keys of any length
+        <Hmac<Sha256> as Mac>::new_from_slice(self.current_secret().expose())
+            .unwrap()
+            .chain_update(label)
+            .chain_update(self.group_id)
+            .chain_update(self.epoch().to_le_bytes())
+    }
+
+    /// Where the commit that ends the current epoch is posted. Slots can
+    /// only be written once, so concurrent commits resolve to the same one
+    /// for everyone.
+    pub fn control_slot(&self) -> SequenceHash {
+        let mut mac = self.control_mac(b"fchan tree control slot");
+        // The first attempt keeps the slot it had before there were others.
+        if self.control_attempt > 0 {
+            mac.update(&self.control_attempt.to_le_bytes());
+        }
+        let hash_bytes: [u8; 32] = mac.finalize().into_bytes().into();
+        hash_bytes.into()
+    }
+
+    /// Moves past an invalid entry in the control slot, to the next one.
+    pub fn skip_control_slot(&mut self) {
+        self.control_attempt += 1;
+    }
+
+    fn control_cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(
+            &self
+                .control_mac(b"fchan tree control")
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    /// Encrypts a control message to the current epoch.
+    pub fn seal_control(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = XNonce::default();
+        OsRng.fill_bytes(&mut nonce);
+        let slot = self.control_slot();
+        let ciphertext = match self.control_cipher().encrypt(
+            &nonce,
+            Payload {
+                msg: message,
+                aad: slot.as_ref(),
+            },
+        ) {
+            Ok(c) => c,
+            Err(e) => bail!(e),
+        };
+
+        let mut buf = nonce.to_vec();
+        buf.extend(ciphertext);
+        Ok(buf)
+    }
+
+    pub fn open_control(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < 24 {
+            bail!("Control message too short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let slot = self.control_slot();
+        match self.control_cipher().decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: slot.as_ref(),
+            },
+        ) {
+            Ok(message) => Ok(message),
+            Err(e) => bail!(e),
+        }
+    }
+
+    /// Prepares a commit that ends the current epoch. Nothing changes until
+    /// it has been posted and passed to [`TreeGroup::apply_own_commit`].
+    /// Returns the commit, the next state, and the welcome for `added`.
+    pub fn commit(
+        &self,
+        identity: &StaticSecret,
+        removed: &[PublicKey],
+        added: &[PublicKey],
+    ) -> anyhow::Result<(Commit, Self, Welcome)> {
+        if self.removed {
+            bail!("Removed from group {}", self.name);
+        }
+        let mut next = self.clone();
+        let before = next.tree.public().clone();
+        let (commit, commit_secret) = next.tree.commit(identity, removed, added)?;
+        let number = self.epoch() + 1;
+        next.push_epoch(number, &commit_secret);
+
+        let welcome = Welcome {
+            group_id: self.group_id,
+            name: self.name.clone(),
+            epoch: number,
+            tree: before,
+            commit: commit.clone(),
+        };
+        Ok((commit, next, welcome))
+    }
+
+    /// Applies a commit posted by another member.
+    pub fn process(&mut self, identity: &StaticSecret, commit: &Commit) -> anyhow::Result<()> {
+        if self.removed {
+            bail!("Removed from group {}", self.name);
+        }
+        let mut tree = self.tree.clone();
+        match tree.process(identity, commit)? {
+            Some(commit_secret) => {
+                self.tree = tree;
+                let number = self.epoch() + 1;
+                self.push_epoch(number, &commit_secret);
+            }
+            None => self.removed = true,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_math() {
+        assert_eq!(parent(0), 1);
+        assert_eq!(parent(2), 1);
+        assert_eq!(parent(1), 3);
+        assert_eq!(parent(5), 3);
+        assert_eq!(sibling(0), 2);
+        assert_eq!(sibling(1), 5);
+        assert_eq!((left(3), right(3)), (1, 5));
+        assert!(is_below(4, 5) && is_below(6, 5) && !is_below(2, 5));
+    }
+
+    #[test]
+    fn members_agree_on_every_epoch() {
+        let secrets = (0..5)
+            .map(|_| StaticSecret::random_from_rng(OsRng))
+            .collect::<Vec<_>>();
+        let keys = secrets.iter().map(PublicKey::from).collect::<Vec<_>>();
+
+        let (mut alice, welcome) = TreeGroup::create(&secrets[0], "team", &keys[1..4]).unwrap();
+        let welcome = Welcome::try_from_bytes(&welcome.to_bytes()).unwrap();
+        let mut others = secrets[1..4]
+            .iter()
+            .map(|s| TreeGroup::join(s, &welcome).unwrap())
+            .collect::<Vec<_>>();
+        for other in &others {
+            assert_eq!(
+                other.current_secret().expose(),
+                alice.current_secret().expose()
+            );
+        }
+
+        // Bob removes Carol and adds Erin.
+        let (commit, next, welcome) = others[0]
+            .commit(&secrets[1], &[keys[2]], &[keys[4]])
+            .unwrap();
+        others[0] = next;
+        let commit = Commit::try_from_bytes(&commit.to_bytes()).unwrap();
+        alice.process(&secrets[0], &commit).unwrap();
+        others[1].process(&secrets[2], &commit).unwrap();
+        others[2].process(&secrets[3], &commit).unwrap();
+        let erin = TreeGroup::join(&secrets[4], &welcome).unwrap();
+
+        assert!(others[1].is_removed());
+        assert_eq!(alice.epoch(), 1);
+        assert_eq!(alice.members().len(), 4);
+        for group in [&others[0], &others[2], &erin] {
+            assert_eq!(
+                group.current_secret().expose(),
+                alice.current_secret().expose()
+            );
+        }
+        assert_ne!(
+            others[1].current_secret().expose(),
+            alice.current_secret().expose()
+        );
+        assert_eq!(alice.control_slot(), erin.control_slot());
+        let sealed = alice.seal_control(b"control").unwrap();
+        assert_eq!(erin.open_control(&sealed).unwrap(), b"control");
+    }
+
+    #[test]
+    fn commits_only_count_from_their_committer() {
+        let secrets = (0..3)
+            .map(|_| StaticSecret::random_from_rng(OsRng))
+            .collect::<Vec<_>>();
+        let keys = secrets.iter().map(PublicKey::from).collect::<Vec<_>>();
+        let (mut alice, welcome) = TreeGroup::create(&secrets[0], "team", &keys[1..]).unwrap();
+        let bob = TreeGroup::join(&secrets[1], &welcome).unwrap();
+        let carol = TreeGroup::join(&secrets[2], &welcome).unwrap();
+
+        // Carol removes Alice in a commit that claims to be from Bob.
+        let (mut forged, _, _) = carol.commit(&secrets[2], &[keys[0]], &[]).unwrap();
+        forged.committer = bob.tree.public().leaf_of(&keys[1]).unwrap();
+        assert!(alice.process(&secrets[0], &forged).is_err());
+        assert!(!alice.is_removed());
+
+        let (mut tampered, _, _) = bob.commit(&secrets[1], &[keys[2]], &[]).unwrap();
+        tampered.removed = vec![alice.tree.public().leaf_of(&keys[0]).unwrap()];
+        assert!(alice.process(&secrets[0], &tampered).is_err());
+        assert!(!alice.is_removed());
+        assert_eq!(alice.epoch(), 0);
+
+        let slot = alice.control_slot();
+        alice.skip_control_slot();
+        assert_ne!(alice.control_slot(), slot);
+        let (commit, _, _) = bob.commit(&secrets[1], &[keys[2]], &[]).unwrap();
+        alice.process(&secrets[0], &commit).unwrap();
+        assert_eq!(alice.epoch(), 1);
+        assert_eq!(alice.control_attempt, 0);
+    }
+}
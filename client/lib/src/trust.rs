@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::VerifyingKey;
+use near_primitives::types::AccountId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+
+const FINGERPRINT_VERSION: u16 = 0;
+const FINGERPRINT_ITERATIONS: usize = 1024;
+
+fn fingerprint_digits(public_key: &PublicKey) -> String {
+    let mut hash: [u8; 32] = <Sha256 as Digest>::new()
+        .chain_update(FINGERPRINT_VERSION.to_le_bytes())
+        .chain_update(public_key.as_bytes())
+        .finalize()
+        .into();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        hash = <Sha256 as Digest>::new()
+            .chain_update(hash)
+            .chain_update(public_key.as_bytes())
+            .finalize()
+            .into();
+    }
+
+    hash.chunks_exact(5)
+        .map(|chunk| {
+            let mut buf = [0u8; 8];
+            buf[..5].copy_from_slice(chunk);
+            format!("{:05}", u64::from_le_bytes(buf) % 100_000)
+        })
+        .collect()
+}
+
+fn group_digits(digits: &str) -> String {
+    digits
+        .as_bytes()
+        .chunks(5)
+        .map(|c| std::str::from_utf8(c).unwrap()) // unwrap ok because digits are ASCII
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A 30-digit, human-comparable representation of a single key.
+pub fn fingerprint(public_key: &PublicKey) -> String {
+    group_digits(&fingerprint_digits(public_key))
+}
+
+/// A 60-digit number that both parties of a conversation compute identically.
+/// If they match when compared out-of-band, neither key has been substituted.
+pub fn safety_number(a: &PublicKey, b: &PublicKey) -> String {
+    let mut halves = [fingerprint_digits(a), fingerprint_digits(b)];
+    halves.sort();
+    group_digits(&halves.concat())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrustedKey {
+    pub public_key: [u8; 32],
+    pub verified: bool,
+    /// Keys this contact used before, oldest first.
+    #[serde(default)]
+    pub previous_keys: Vec<[u8; 32]>,
+    /// The signing keys first seen for the current and previous keys, as
+    /// (key, signing key) pairs.
+    #[serde(default)]
+    pub signing_keys: Vec<([u8; 32], [u8; 32])>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyObservation {
+    FirstSeen,
+    Unchanged,
+    Changed { previous: TrustedKey },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub account_id: AccountId,
+    pub previous_key: PublicKey,
+    pub new_key: PublicKey,
+    pub was_verified: bool,
+}
+
+/// Trust-on-first-use record of the key each contact was first seen with.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrustStore {
+    contacts: HashMap<AccountId, TrustedKey>,
+}
+
+impl TrustStore {
+    pub fn get(&self, account_id: &AccountId) -> Option<&TrustedKey> {
+        self.contacts.get(account_id)
+    }
+
+    pub fn account_ids(&self) -> impl Iterator<Item = &AccountId> {
+        self.contacts.keys()
+    }
+
+    /// Records `public_key` for `account_id`. A changed key replaces the
+    /// previous one and starts out unverified.
+    pub fn observe(&mut self, account_id: &AccountId, public_key: &PublicKey) -> KeyObservation {
+        let mut trusted_key = TrustedKey {
+            public_key: public_key.to_bytes(),
+            verified: false,
+            previous_keys: vec![],
+            signing_keys: vec![],
+        };
+
+        match self.contacts.get(account_id) {
+            None => {
+                self.contacts.insert(account_id.clone(), trusted_key);
+                KeyObservation::FirstSeen
+            }
+            Some(existing) if &existing.public_key == public_key.as_bytes() => {
+                KeyObservation::Unchanged
+            }
+            Some(existing) => {
+                trusted_key.previous_keys = existing.previous_keys.clone();
+                trusted_key.previous_keys.push(existing.public_key);
+                trusted_key.signing_keys = existing.signing_keys.clone();
+                // unwrap ok because we just checked that the entry exists
+                let previous = self
+                    .contacts
+                    .insert(account_id.clone(), trusted_key)
+                    .unwrap();
+                KeyObservation::Changed { previous }
+            }
+        }
+    }
+
+    /// Binds `signing_key` to the current key of `account_id`, unless
+    /// another signing key is bound to it already. Returns whether
+    /// `signing_key` is the bound one.
+    pub fn bind_signing_key(
+        &mut self,
+        account_id: &AccountId,
+        public_key: &PublicKey,
+        signing_key: &VerifyingKey,
+    ) -> bool {
+        let Some(trusted_key) = self.contacts.get_mut(account_id) else {
+            return false;
+        };
+        if &trusted_key.public_key != public_key.as_bytes() {
+            return false;
+        }
+        match trusted_key
+            .signing_keys
+            .iter()
+            .find(|(k, _)| k == public_key.as_bytes())
+        {
+            Some((_, bound)) => bound == signing_key.as_bytes(),
+            None => {
+                trusted_key
+                    .signing_keys
+                    .push((public_key.to_bytes(), signing_key.to_bytes()));
+                true
+            }
+        }
+    }
+
+    /// The signing key bound to `public_key`, one of the current or previous
+    /// keys of `account_id`.
+    pub fn signing_key(
+        &self,
+        account_id: &AccountId,
+        public_key: &[u8; 32],
+    ) -> Option<VerifyingKey> {
+        let (_, signing_key) = self
+            .contacts
+            .get(account_id)?
+            .signing_keys
+            .iter()
+            .find(|(k, _)| k == public_key)?;
+        VerifyingKey::from_bytes(signing_key).ok()
+    }
+
+    /// Marks the currently trusted key for `account_id` as verified. Returns
+    /// `false` if the contact is unknown.
+    pub fn mark_verified(&mut self, account_id: &AccountId) -> bool {
+        match self.contacts.get_mut(account_id) {
+            Some(trusted_key) => {
+                trusted_key.verified = true;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use x25519_dalek::StaticSecret;
+
+    use super::*;
+
+    #[test]
+    fn safety_number_is_symmetric() {
+        let a = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let b = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+
+        assert_eq!(safety_number(&a, &b), safety_number(&b, &a));
+        assert_eq!(safety_number(&a, &b).replace(' ', "").len(), 60);
+        assert_ne!(fingerprint(&a), fingerprint(&b));
+    }
+
+    #[test]
+    fn observe_detects_key_change() {
+        let account_id: AccountId = "alice.near".parse().unwrap();
+        let a = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let b = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let mut store = TrustStore::default();
+
+        assert_eq!(store.observe(&account_id, &a), KeyObservation::FirstSeen);
+        assert!(store.mark_verified(&account_id));
+        assert_eq!(store.observe(&account_id, &a), KeyObservation::Unchanged);
+        assert_eq!(
+            store.observe(&account_id, &b),
+            KeyObservation::Changed {
+                previous: TrustedKey {
+                    public_key: a.to_bytes(),
+                    verified: true,
+                    previous_keys: vec![],
+                    signing_keys: vec![],
+                },
+            },
+        );
+        assert!(!store.get(&account_id).unwrap().verified);
+        assert_eq!(
+            store.get(&account_id).unwrap().previous_keys,
+            vec![a.to_bytes()]
+        );
+    }
+
+    #[test]
+    fn signing_keys_stay_bound_to_their_key() {
+        let account_id: AccountId = "alice.near".parse().unwrap();
+        let a = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let b = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        let signing_key = SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let other_signing_key = SigningKey::from_bytes(&[2; 32]).verifying_key();
+        let mut store = TrustStore::default();
+
+        assert!(!store.bind_signing_key(&account_id, &a, &signing_key));
+        store.observe(&account_id, &a);
+        assert!(store.bind_signing_key(&account_id, &a, &signing_key));
+        assert!(!store.bind_signing_key(&account_id, &a, &other_signing_key));
+
+        store.observe(&account_id, &b);
+        assert!(!store.bind_signing_key(&account_id, &a, &other_signing_key));
+        assert_eq!(
+            store.signing_key(&account_id, a.as_bytes()),
+            Some(signing_key)
+        );
+        assert_eq!(store.signing_key(&account_id, b.as_bytes()), None);
+    }
+}
@@ -6,7 +6,7 @@ use near_primitives::{
     hash::CryptoHash,
     transaction::{Action, Transaction, TransactionV0},
     types::{AccountId, BlockReference, Finality},
-    views::{AccessKeyView, FinalExecutionOutcomeView, QueryRequest},
+    views::{AccessKeyView, FinalExecutionOutcomeView, FinalExecutionStatus, QueryRequest},
 };
 use serde::de::DeserializeOwned;
 
@@ -102,6 +102,30 @@ impl Wallet {
         Ok(result)
     }
 
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        receiver_id: AccountId,
+        actions: Vec<Action>,
+    ) -> anyhow::Result<T> {
+        let outcome = self.transact(receiver_id, actions).await?;
+
+        let value = match outcome.status {
+            FinalExecutionStatus::SuccessValue(v) => v,
+            FinalExecutionStatus::Failure(e) => bail!("Transaction failed: {e}"),
+            s => bail!("Transaction did not complete: {s:?}"),
+        };
+
+        let value: T = serde_json::from_slice(&value)?;
+
+        Ok(value)
+    }
+
+    /// The chain ID reported by the RPC node, e.g. `mainnet` or `testnet`.
+    pub async fn chain_id(&self) -> anyhow::Result<String> {
+        let status = self.rpc.send(methods::status::RpcStatusRequest).await?;
+        Ok(status.chain_id)
+    }
+
     pub async fn view<T: DeserializeOwned>(
         &self,
         account_id: AccountId,
@@ -1,13 +1,29 @@
 use near_sdk::{
-    collections::LookupMap, env, json_types::Base64VecU8, near, require, AccountId,
-    BorshStorageKey, PanicOnDefault, PromiseOrValue,
+    collections::{LookupMap, UnorderedMap},
+    env,
+    json_types::{Base64VecU8, U64},
+    near, require, AccountId, BorshStorageKey, NearToken, PanicOnDefault, Promise, PromiseOrValue,
 };
 use near_sdk_contract_tools::{event, standard::nep297::Event};
 
+const MAX_ONE_TIME_PREKEYS: usize = 100;
+const MAX_KEM_PREKEY_LENGTH: usize = 1568;
+const MAX_BATCH_SIZE: usize = 100;
+const MAX_RECORD_LIST_LENGTH: usize = 32;
+const MAX_RECORD_STRING_LENGTH: usize = 64;
+const LEGACY_ALGORITHM: &str = "x25519";
+const DEFAULT_PAGE_SIZE: u64 = 100;
+const MAX_PAGE_SIZE: u64 = 500;
+/// Paid for each one-time prekey claimed, to the account that published it.
+const PREKEY_CLAIM_FEE: NearToken = NearToken::from_millinear(1);
+
 #[derive(Debug, BorshStorageKey)]
 #[near]
 enum StorageKey {
-    KeyMap,
+    LegacyKeys,
+    Prekeys,
+    OneTimePrekeys,
+    Keys,
 }
 
 #[event(
@@ -22,10 +38,141 @@ enum PublicKeyManagerEvent {
     },
 }
 
+/// A public key together with the capabilities of the client that published
+/// it. Algorithm and feature names are opaque to the contract.
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [borsh, json])]
+pub struct KeyRecord {
+    pub algorithm: String,
+    pub public_key: Base64VecU8,
+    pub protocol_versions: Vec<u32>,
+    pub features: Vec<String>,
+}
+
+impl KeyRecord {
+    fn legacy(public_key: Base64VecU8) -> Self {
+        Self {
+            algorithm: LEGACY_ALGORITHM.to_string(),
+            public_key,
+            protocol_versions: vec![0],
+            features: vec![],
+        }
+    }
+
+    fn validate(&self) {
+        require!(
+            self.algorithm.len() <= MAX_RECORD_STRING_LENGTH,
+            "Algorithm name too long"
+        );
+        require!(
+            self.protocol_versions.len() <= MAX_RECORD_LIST_LENGTH,
+            "Too many protocol versions"
+        );
+        require!(
+            self.features.len() <= MAX_RECORD_LIST_LENGTH,
+            "Too many features"
+        );
+        require!(
+            self.features
+                .iter()
+                .all(|f| f.len() <= MAX_RECORD_STRING_LENGTH),
+            "Feature name too long"
+        );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [json])]
+pub struct SigningKeyRecord {
+    pub identity_key: Base64VecU8,
+    pub signing_key: Base64VecU8,
+}
+
+/// How a [`KeyRecord`] is stored, so that records written by older versions
+/// of the contract can still be read when the record type changes.
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [borsh])]
+enum VersionedKeyRecord {
+    /// A bare x25519 key, as stored before key records.
+    Legacy(Base64VecU8),
+    V1(KeyRecord),
+}
+
+impl From<VersionedKeyRecord> for KeyRecord {
+    fn from(record: VersionedKeyRecord) -> Self {
+        match record {
+            VersionedKeyRecord::Legacy(public_key) => KeyRecord::legacy(public_key),
+            VersionedKeyRecord::V1(record) => record,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [json])]
+pub struct KeyRecordEntry {
+    pub account_id: AccountId,
+    pub record: KeyRecord,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [borsh, json])]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub public_key: Base64VecU8,
+    pub signature: Base64VecU8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [borsh, json])]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub public_key: Base64VecU8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [borsh])]
+pub struct PrekeyRecord {
+    pub signing_key: Base64VecU8,
+    pub signed_prekey: SignedPrekey,
+    pub kem_prekey: Option<SignedPrekey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[near(serializers = [json])]
+pub struct PrekeyBundle {
+    pub identity_key: Base64VecU8,
+    pub signing_key: Base64VecU8,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekey: Option<OneTimePrekey>,
+    pub kem_prekey: Option<SignedPrekey>,
+}
+
+/// The state before keys were stored in an iterable map.
+#[near]
+struct LegacyState {
+    key_map: LookupMap<AccountId, Base64VecU8>,
+}
+
 #[near(contract_state)]
 #[derive(PanicOnDefault)]
 pub struct PublicKeyManagerContract {
-    key_map: LookupMap<AccountId, Base64VecU8>,
+    /// Keys set before the registry became iterable, until they are moved to
+    /// `key_map` by [`PublicKeyManagerContract::migrate_keys`] or replaced by
+    /// their owner.
+    legacy_key_map: LookupMap<AccountId, Base64VecU8>,
+    key_map: UnorderedMap<AccountId, VersionedKeyRecord>,
+    prekey_map: LookupMap<AccountId, PrekeyRecord>,
+    one_time_prekey_map: LookupMap<AccountId, Vec<OneTimePrekey>>,
+}
+
+fn refund_storage_fee(initial_storage_usage: u64) -> PromiseOrValue<()> {
+    if let Some(p) =
+        near_sdk_contract_tools::utils::apply_storage_fee_and_refund(initial_storage_usage, 0)
+    {
+        PromiseOrValue::Promise(p)
+    } else {
+        PromiseOrValue::Value(())
+    }
 }
 
 #[near]
@@ -33,38 +180,417 @@ impl PublicKeyManagerContract {
     #[init]
     pub fn new() -> Self {
         Self {
-            key_map: LookupMap::new(StorageKey::KeyMap),
+            legacy_key_map: LookupMap::new(StorageKey::LegacyKeys),
+            key_map: UnorderedMap::new(StorageKey::Keys),
+            prekey_map: LookupMap::new(StorageKey::Prekeys),
+            one_time_prekey_map: LookupMap::new(StorageKey::OneTimePrekeys),
         }
     }
 
+    /// Upgrades the state of a contract deployed before the registry became
+    /// iterable. Existing keys stay readable, and are listed once they have
+    /// been moved with [`PublicKeyManagerContract::migrate_keys`].
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let LegacyState { key_map } =
+            env::state_read().unwrap_or_else(|| env::panic_str("No state to migrate"));
+        Self {
+            legacy_key_map: key_map,
+            ..Self::new()
+        }
+    }
+
+    /// Moves the keys of `account_ids` from the legacy map into the iterable
+    /// one. The legacy map cannot be enumerated, so the account IDs have to
+    /// be collected from the `PublicKeyChange` events. Returns the number of
+    /// keys moved.
+    #[private]
+    pub fn migrate_keys(&mut self, account_ids: Vec<AccountId>) -> u32 {
+        require!(account_ids.len() <= MAX_BATCH_SIZE, "Too many account IDs");
+        let mut moved = 0;
+        for account_id in account_ids {
+            if let Some(public_key) = self.legacy_key_map.remove(&account_id) {
+                self.key_map
+                    .insert(&account_id, &VersionedKeyRecord::Legacy(public_key));
+                moved += 1;
+            }
+        }
+        moved
+    }
+
     pub fn get_public_key(&self, account_id: AccountId) -> Option<Base64VecU8> {
-        self.key_map.get(&account_id)
+        self.key_record(&account_id).map(|r| r.public_key)
+    }
+
+    pub fn get_public_keys(&self, account_ids: Vec<AccountId>) -> Vec<Option<Base64VecU8>> {
+        require!(account_ids.len() <= MAX_BATCH_SIZE, "Too many account IDs");
+        account_ids
+            .iter()
+            .map(|account_id| self.key_record(account_id).map(|r| r.public_key))
+            .collect()
+    }
+
+    pub fn get_key_record(&self, account_id: AccountId) -> Option<KeyRecord> {
+        self.key_record(&account_id)
+    }
+
+    pub fn get_key_records(&self, account_ids: Vec<AccountId>) -> Vec<Option<KeyRecord>> {
+        require!(account_ids.len() <= MAX_BATCH_SIZE, "Too many account IDs");
+        account_ids
+            .iter()
+            .map(|account_id| self.key_record(account_id))
+            .collect()
+    }
+
+    pub fn get_public_key_count(&self) -> U64 {
+        self.key_map.len().into()
     }
 
+    /// Pages through every registered key so that clients can keep a local
+    /// copy of the registry instead of revealing individual lookups. Keys
+    /// that have not been moved by [`PublicKeyManagerContract::migrate_keys`]
+    /// yet are not listed.
+    pub fn list_key_records(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<KeyRecordEntry> {
+        let keys = self.key_map.keys_as_vector();
+        let values = self.key_map.values_as_vector();
+        let from_index = from_index.map_or(0, u64::from);
+        let limit = limit
+            .map_or(DEFAULT_PAGE_SIZE, u64::from)
+            .min(MAX_PAGE_SIZE);
+        let end = from_index.saturating_add(limit).min(keys.len());
+
+        (from_index..end)
+            .filter_map(|i| {
+                Some(KeyRecordEntry {
+                    account_id: keys.get(i)?,
+                    record: values.get(i)?.into(),
+                })
+            })
+            .collect()
+    }
+
+    /// Sets a bare x25519 key, as published by clients that predate key
+    /// records.
     #[payable]
     pub fn set_public_key(&mut self, public_key: Option<Base64VecU8>) -> PromiseOrValue<()> {
+        self.set_key_record(public_key.map(KeyRecord::legacy))
+    }
+
+    #[payable]
+    pub fn set_key_record(&mut self, record: Option<KeyRecord>) -> PromiseOrValue<()> {
         require!(!env::attached_deposit().is_zero(), "Requires deposit");
         let initial_storage_usage = env::storage_usage();
 
         let predecessor = env::predecessor_account_id();
-        if let Some(public_key) = public_key.as_ref() {
-            self.key_map.insert(&predecessor, public_key);
+        self.legacy_key_map.remove(&predecessor);
+        if let Some(record) = record.as_ref() {
+            record.validate();
+            self.key_map
+                .insert(&predecessor, &VersionedKeyRecord::V1(record.clone()));
         } else {
+            // Prekeys are signed for the removed key, so they go with it.
             self.key_map.remove(&predecessor);
+            self.prekey_map.remove(&predecessor);
+            self.one_time_prekey_map.remove(&predecessor);
         }
 
         PublicKeyManagerEvent::PublicKeyChange {
             account_id: env::predecessor_account_id(),
-            public_key,
+            public_key: record.map(|r| r.public_key),
         }
         .emit();
 
-        if let Some(p) =
-            near_sdk_contract_tools::utils::apply_storage_fee_and_refund(initial_storage_usage, 0)
-        {
-            PromiseOrValue::Promise(p)
+        refund_storage_fee(initial_storage_usage)
+    }
+
+    /// The key that signs the prekeys of `account_id`, together with the key
+    /// it was published for, so that clients can bind the two without
+    /// claiming prekeys.
+    pub fn get_signing_key(&self, account_id: AccountId) -> Option<SigningKeyRecord> {
+        Some(SigningKeyRecord {
+            identity_key: self.key_record(&account_id)?.public_key,
+            signing_key: self.prekey_map.get(&account_id)?.signing_key,
+        })
+    }
+
+    pub fn get_one_time_prekey_count(&self, account_id: AccountId) -> u32 {
+        self.one_time_prekey_map
+            .get(&account_id)
+            .map_or(0, |v| v.len() as u32)
+    }
+
+    /// Replaces the caller's signed prekey, post-quantum KEM prekey and
+    /// one-time prekey pool.
+    #[payable]
+    pub fn set_prekeys(
+        &mut self,
+        signing_key: Base64VecU8,
+        signed_prekey: SignedPrekey,
+        kem_prekey: Option<SignedPrekey>,
+        one_time_prekeys: Vec<OneTimePrekey>,
+    ) -> PromiseOrValue<()> {
+        require!(!env::attached_deposit().is_zero(), "Requires deposit");
+        require!(
+            one_time_prekeys.len() <= MAX_ONE_TIME_PREKEYS,
+            "Too many one-time prekeys"
+        );
+        require!(
+            kem_prekey
+                .as_ref()
+                .is_none_or(|k| k.public_key.0.len() <= MAX_KEM_PREKEY_LENGTH),
+            "KEM prekey too long"
+        );
+        let initial_storage_usage = env::storage_usage();
+
+        let predecessor = env::predecessor_account_id();
+        self.prekey_map.insert(
+            &predecessor,
+            &PrekeyRecord {
+                signing_key,
+                signed_prekey,
+                kem_prekey,
+            },
+        );
+        self.one_time_prekey_map
+            .insert(&predecessor, &one_time_prekeys);
+
+        refund_storage_fee(initial_storage_usage)
+    }
+
+    /// Appends to the caller's one-time prekey pool.
+    #[payable]
+    pub fn add_one_time_prekeys(
+        &mut self,
+        one_time_prekeys: Vec<OneTimePrekey>,
+    ) -> PromiseOrValue<()> {
+        require!(!env::attached_deposit().is_zero(), "Requires deposit");
+        let initial_storage_usage = env::storage_usage();
+
+        let predecessor = env::predecessor_account_id();
+        require!(
+            self.prekey_map.contains_key(&predecessor),
+            "Signed prekey not set"
+        );
+        let mut pool = self
+            .one_time_prekey_map
+            .get(&predecessor)
+            .unwrap_or_default();
+        pool.extend(one_time_prekeys);
+        require!(
+            pool.len() <= MAX_ONE_TIME_PREKEYS,
+            "Too many one-time prekeys"
+        );
+        self.one_time_prekey_map.insert(&predecessor, &pool);
+
+        refund_storage_fee(initial_storage_usage)
+    }
+
+    /// Returns the prekey bundle for `account_id`, removing one one-time
+    /// prekey from the pool so that no other caller receives it. Each
+    /// one-time prekey costs [`PREKEY_CLAIM_FEE`], which goes to `account_id`
+    /// to pay for replenishing its pool, so that draining someone's pool is
+    /// not free. The rest of the deposit is refunded.
+    #[payable]
+    pub fn claim_prekey_bundle(&mut self, account_id: AccountId) -> Option<PrekeyBundle> {
+        let deposit = env::attached_deposit();
+        require!(deposit >= PREKEY_CLAIM_FEE, "Requires deposit");
+
+        let bundle = self.take_prekey_bundle(&account_id);
+        let refund = if bundle.as_ref().is_some_and(|b| b.one_time_prekey.is_some()) {
+            Promise::new(account_id).transfer(PREKEY_CLAIM_FEE);
+            deposit.saturating_sub(PREKEY_CLAIM_FEE)
         } else {
-            PromiseOrValue::Value(())
+            deposit
+        };
+        if !refund.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+
+        bundle
+    }
+}
+
+impl PublicKeyManagerContract {
+    fn key_record(&self, account_id: &AccountId) -> Option<KeyRecord> {
+        self.key_map
+            .get(account_id)
+            .map(KeyRecord::from)
+            .or_else(|| self.legacy_key_map.get(account_id).map(KeyRecord::legacy))
+    }
+
+    fn take_prekey_bundle(&mut self, account_id: &AccountId) -> Option<PrekeyBundle> {
+        let identity_key = self.key_record(account_id)?.public_key;
+        let PrekeyRecord {
+            signing_key,
+            signed_prekey,
+            kem_prekey,
+        } = self.prekey_map.get(account_id)?;
+
+        let mut pool = self.one_time_prekey_map.get(account_id).unwrap_or_default();
+        let one_time_prekey = pool.pop();
+        if one_time_prekey.is_some() {
+            self.one_time_prekey_map.insert(account_id, &pool);
         }
+
+        Some(PrekeyBundle {
+            identity_key,
+            signing_key,
+            signed_prekey,
+            one_time_prekey,
+            kem_prekey,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::{test_utils::VMContextBuilder, testing_env};
+
+    use super::*;
+
+    fn alice() -> AccountId {
+        "alice.near".parse().unwrap()
+    }
+
+    fn bob() -> AccountId {
+        "bob.near".parse().unwrap()
+    }
+
+    fn call_as(account_id: AccountId, deposit: NearToken) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(account_id)
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    fn signed_prekey() -> SignedPrekey {
+        SignedPrekey {
+            id: 1,
+            public_key: vec![1; 32].into(),
+            signature: vec![2; 64].into(),
+        }
+    }
+
+    fn one_time_prekeys(n: u32) -> Vec<OneTimePrekey> {
+        (0..n)
+            .map(|id| OneTimePrekey {
+                id,
+                public_key: vec![id as u8; 32].into(),
+            })
+            .collect()
+    }
+
+    fn contract_with_prekeys(n: u32) -> PublicKeyManagerContract {
+        call_as(alice(), NearToken::from_near(1));
+        let mut contract = PublicKeyManagerContract::new();
+        contract.set_public_key(Some(vec![0; 32].into()));
+        contract.set_prekeys(
+            vec![3; 32].into(),
+            signed_prekey(),
+            None,
+            one_time_prekeys(n),
+        );
+        contract
+    }
+
+    #[test]
+    fn set_prekeys_replaces_the_pool() {
+        let mut contract = contract_with_prekeys(3);
+        assert_eq!(contract.get_one_time_prekey_count(alice()), 3);
+
+        contract.set_prekeys(
+            vec![3; 32].into(),
+            signed_prekey(),
+            None,
+            one_time_prekeys(1),
+        );
+        assert_eq!(contract.get_one_time_prekey_count(alice()), 1);
+    }
+
+    #[test]
+    fn claims_take_distinct_one_time_prekeys() {
+        let mut contract = contract_with_prekeys(2);
+
+        call_as(bob(), PREKEY_CLAIM_FEE);
+        let first = contract.claim_prekey_bundle(alice()).unwrap();
+        let second = contract.claim_prekey_bundle(alice()).unwrap();
+
+        assert_eq!(first.signed_prekey, signed_prekey());
+        assert_ne!(first.one_time_prekey, second.one_time_prekey);
+        assert_eq!(contract.get_one_time_prekey_count(alice()), 0);
+    }
+
+    #[test]
+    fn exhausted_pools_still_return_the_signed_prekey() {
+        let mut contract = contract_with_prekeys(1);
+
+        call_as(bob(), PREKEY_CLAIM_FEE);
+        assert!(contract
+            .claim_prekey_bundle(alice())
+            .unwrap()
+            .one_time_prekey
+            .is_some());
+        let bundle = contract.claim_prekey_bundle(alice()).unwrap();
+        assert_eq!(bundle.one_time_prekey, None);
+        assert_eq!(bundle.signed_prekey, signed_prekey());
+
+        assert!(contract.claim_prekey_bundle(bob()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires deposit")]
+    fn claims_require_the_fee() {
+        let mut contract = contract_with_prekeys(1);
+
+        call_as(bob(), NearToken::from_yoctonear(0));
+        contract.claim_prekey_bundle(alice());
+    }
+
+    #[test]
+    fn migrated_keys_stay_readable_and_become_listed() {
+        call_as(alice(), NearToken::from_near(1));
+        let mut key_map = LookupMap::new(StorageKey::LegacyKeys);
+        key_map.insert(&alice(), &Base64VecU8::from(vec![7; 32]));
+        key_map.insert(&bob(), &Base64VecU8::from(vec![8; 32]));
+        env::state_write(&LegacyState { key_map });
+
+        let mut contract = PublicKeyManagerContract::migrate();
+        assert_eq!(contract.get_public_key(alice()), Some(vec![7; 32].into()));
+        assert!(contract.list_key_records(None, None).is_empty());
+
+        assert_eq!(contract.migrate_keys(vec![alice(), alice()]), 1);
+        let listed = contract.list_key_records(None, None);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].record, KeyRecord::legacy(vec![7; 32].into()));
+
+        call_as(bob(), NearToken::from_near(1));
+        contract.set_key_record(None);
+        assert_eq!(contract.get_public_key(bob()), None);
+    }
+
+    #[test]
+    fn signing_keys_come_with_their_identity_key() {
+        let contract = contract_with_prekeys(0);
+
+        let record = contract.get_signing_key(alice()).unwrap();
+        assert_eq!(record.identity_key, vec![0; 32].into());
+        assert_eq!(record.signing_key, vec![3; 32].into());
+        assert!(contract.get_signing_key(bob()).is_none());
+    }
+
+    #[test]
+    fn removing_the_key_removes_prekeys() {
+        let mut contract = contract_with_prekeys(1);
+
+        contract.set_key_record(None);
+
+        assert_eq!(contract.get_one_time_prekey_count(alice()), 0);
+        call_as(bob(), PREKEY_CLAIM_FEE);
+        assert!(contract.claim_prekey_bundle(alice()).is_none());
     }
 }